@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use super::{
+    blockchain::{Blockchain, ChainError},
+    blocks::Block,
+    config::GLOBAL_CONFIG,
+    schema::{ADDRESS_INDEX_BEST_BLOCK, METADATA_TREE},
+    storage::StorageTransaction,
+    transaction::{OutPoint, OUTPOINT_KEY_LEN},
+    utxoset::UTXOEntry,
+};
+
+pub const ADDRESS_UTXO_TREE: &str = "address_utxo";
+pub const ADDRESS_TX_TREE: &str = "address_txs";
+
+pub struct AddressIndex {
+    blockchain: Blockchain,
+}
+
+impl AddressIndex {
+    pub fn new(blockchain: Blockchain) -> Self {
+        Self { blockchain }
+    }
+
+    pub fn is_enabled() -> bool {
+        GLOBAL_CONFIG.is_address_index_enabled()
+    }
+
+    // The index when it is enabled and synced to the tip. A stale index is
+    // only rebuilt when the chain is written to, so readers fall back to
+    // scanning the UTXO set.
+    pub fn open(blockchain: &Blockchain) -> Option<Self> {
+        if !Self::is_enabled() {
+            return None;
+        }
+
+        let index = Self::new(blockchain.clone());
+        if !index.is_synced_to(&blockchain.get_latest_blk_hash()) {
+            warn!("Address index is out of date, run reindex to rebuild it");
+            return None;
+        }
+        Some(index)
+    }
+
+    pub fn is_synced_to(&self, block_hash: &str) -> bool {
+        let storage = self.blockchain.get_storage();
+        match storage
+            .get(METADATA_TREE, ADDRESS_INDEX_BEST_BLOCK.as_bytes())
+            .unwrap()
+        {
+            Some(best_block) => best_block.eq(block_hash.as_bytes()),
+            None => false,
+        }
+    }
+
+    pub fn find_outpoints(&self, pub_key_hash: &[u8]) -> Vec<OutPoint> {
+        let storage = self.blockchain.get_storage();
+        let mut outpoints = vec![];
+        for item in storage.scan_prefix(ADDRESS_UTXO_TREE, pub_key_hash) {
+            let (k, _) = item.unwrap();
+            outpoints.push(OutPoint::from_key(&k[pub_key_hash.len()..]));
+        }
+        outpoints
+    }
+
+    pub fn find_transactions(&self, pub_key_hash: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let storage = self.blockchain.get_storage();
+        let mut txs = vec![];
+        for item in storage.scan_prefix(ADDRESS_TX_TREE, pub_key_hash) {
+            let (k, _) = item.unwrap();
+            let (height, txid) = k[pub_key_hash.len()..].split_at(8);
+            let height = u64::from_be_bytes(height.try_into().unwrap()) as usize;
+            txs.push((height, txid.to_vec()));
+        }
+        txs
+    }
+
+    pub fn connect_block(
+        tx_storage: &dyn StorageTransaction,
+        block: &Block,
+        spent: &HashMap<OutPoint, UTXOEntry>,
+    ) -> Result<(), ChainError> {
+        for tx in block.get_transactions() {
+            let mut touched = vec![];
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    let outpoint = vin.get_outpoint();
+                    if let Some(entry) = spent.get(&outpoint) {
+                        let key = utxo_key(entry.get_pub_key_hash(), &outpoint);
+                        tx_storage.remove(ADDRESS_UTXO_TREE, &key)?;
+                        touched.push(entry.get_pub_key_hash().to_vec());
+                    }
+                }
+            }
+
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                let outpoint = OutPoint::new(tx.get_id(), idx);
+                let key = utxo_key(out.get_pub_key_hash(), &outpoint);
+                tx_storage.insert(ADDRESS_UTXO_TREE, &key, &[])?;
+                touched.push(out.get_pub_key_hash().to_vec());
+            }
+
+            for pub_key_hash in touched {
+                let key = tx_key(&pub_key_hash, block.get_height(), tx.get_id());
+                tx_storage.insert(ADDRESS_TX_TREE, &key, &[])?;
+            }
+        }
+        let best_block = block.get_hash().as_bytes();
+        tx_storage.insert(
+            METADATA_TREE,
+            ADDRESS_INDEX_BEST_BLOCK.as_bytes(),
+            best_block,
+        )?;
+        Ok(())
+    }
+
+    pub fn disconnect_block(
+        tx_storage: &dyn StorageTransaction,
+        block: &Block,
+        spent: &HashMap<OutPoint, UTXOEntry>,
+    ) -> Result<(), ChainError> {
+        for tx in block.get_transactions().iter().rev() {
+            let mut touched = vec![];
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                let outpoint = OutPoint::new(tx.get_id(), idx);
+                tx_storage.remove(
+                    ADDRESS_UTXO_TREE,
+                    &utxo_key(out.get_pub_key_hash(), &outpoint),
+                )?;
+                touched.push(out.get_pub_key_hash().to_vec());
+            }
+
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    let outpoint = vin.get_outpoint();
+                    if let Some(entry) = spent.get(&outpoint) {
+                        let key = utxo_key(entry.get_pub_key_hash(), &outpoint);
+                        tx_storage.insert(ADDRESS_UTXO_TREE, &key, &[])?;
+                        touched.push(entry.get_pub_key_hash().to_vec());
+                    }
+                }
+            }
+
+            for pub_key_hash in touched {
+                let key = tx_key(&pub_key_hash, block.get_height(), tx.get_id());
+                tx_storage.remove(ADDRESS_TX_TREE, &key)?;
+            }
+        }
+        let best_block = block.get_prev_block_hash();
+        tx_storage.insert(
+            METADATA_TREE,
+            ADDRESS_INDEX_BEST_BLOCK.as_bytes(),
+            best_block.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    pub fn reindex(&self) {
+        if self.blockchain.is_pruned() {
+            panic!("Unable to rebuild the address index: old block bodies have been pruned");
+        }
+        let storage = self.blockchain.get_storage();
+        storage
+            .remove(METADATA_TREE, ADDRESS_INDEX_BEST_BLOCK.as_bytes())
+            .unwrap();
+        storage.clear(ADDRESS_UTXO_TREE).unwrap();
+        storage.clear(ADDRESS_TX_TREE).unwrap();
+
+        let mut blocks = vec![];
+        let mut iterator = self.blockchain.iterator();
+        while let Some(block) = iterator.next() {
+            blocks.push(block);
+        }
+
+        let mut created: HashMap<OutPoint, UTXOEntry> = HashMap::new();
+        for block in blocks.iter().rev() {
+            let mut spent = HashMap::new();
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in tx.get_vin() {
+                        let outpoint = vin.get_outpoint();
+                        if let Some(entry) = created.remove(&outpoint) {
+                            spent.insert(outpoint, entry);
+                        }
+                    }
+                }
+                for (idx, out) in tx.get_vout().iter().enumerate() {
+                    let entry = UTXOEntry::new(out, block.get_height(), tx.is_coinbase());
+                    created.insert(OutPoint::new(tx.get_id(), idx), entry);
+                }
+            }
+
+            storage
+                .transaction(
+                    &[METADATA_TREE, ADDRESS_UTXO_TREE, ADDRESS_TX_TREE],
+                    &|tx| Ok(Self::connect_block(tx, block, &spent)?),
+                )
+                .expect("unable to rebuild the address index");
+        }
+    }
+}
+
+fn utxo_key(pub_key_hash: &[u8], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(pub_key_hash.len() + OUTPOINT_KEY_LEN);
+    key.extend(pub_key_hash);
+    key.extend(outpoint.to_key());
+    key
+}
+
+fn tx_key(pub_key_hash: &[u8], height: usize, txid: &[u8]) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend((height as u64).to_be_bytes());
+    key.extend(txid);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        test_fixtures::{mine, spend_coinbase, test_wallet},
+        transaction::Transaction,
+        utxoset::UTXOSet,
+        wallets::{get_pub_key_hash, Wallet},
+    };
+
+    fn pub_key_hash(wallet: &Wallet) -> Vec<u8> {
+        get_pub_key_hash(&wallet.get_address())
+    }
+
+    fn assert_spend_indexed(
+        index: &AddressIndex,
+        miner: &Wallet,
+        recipient: &Wallet,
+        coinbase_out: &OutPoint,
+        spend: &Transaction,
+        height: usize,
+    ) {
+        let recipient_outs = index.find_outpoints(&pub_key_hash(recipient));
+        assert_eq!(recipient_outs, vec![OutPoint::new(spend.get_id(), 0)]);
+        let miner_outs = index.find_outpoints(&pub_key_hash(miner));
+        assert!(!miner_outs.contains(coinbase_out));
+        assert!(miner_outs.contains(&OutPoint::new(spend.get_id(), 1)));
+
+        let recipient_txs = index.find_transactions(&pub_key_hash(recipient));
+        assert_eq!(recipient_txs, vec![(height, spend.get_id_bytes())]);
+        let miner_txs = index.find_transactions(&pub_key_hash(miner));
+        assert!(miner_txs.contains(&(height, spend.get_id_bytes())));
+        assert!(miner_txs.contains(&(1, coinbase_out.get_txid().to_vec())));
+    }
+
+    #[test]
+    fn reindex_follows_spent_outputs() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let block = mine(&blockchain, &miner, std::slice::from_ref(&spend));
+
+        let index = AddressIndex::new(blockchain.clone());
+        assert!(!index.is_synced_to(block.get_hash()));
+        index.reindex();
+        assert!(index.is_synced_to(block.get_hash()));
+        assert_spend_indexed(
+            &index,
+            &miner,
+            &recipient,
+            &coinbase_out,
+            &spend,
+            block.get_height(),
+        );
+    }
+
+    #[test]
+    fn connect_block_moves_spent_outputs() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let index = AddressIndex::new(blockchain.clone());
+        index.reindex();
+
+        let entry = UTXOSet::new(blockchain.clone())
+            .get_entry(&coinbase_out)
+            .unwrap();
+        let block = mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        assert!(index.is_synced_to(&block.get_prev_block_hash()));
+        let spent = HashMap::from([(coinbase_out.clone(), entry)]);
+        blockchain
+            .get_storage()
+            .transaction(
+                &[METADATA_TREE, ADDRESS_UTXO_TREE, ADDRESS_TX_TREE],
+                &|tx| Ok(AddressIndex::connect_block(tx, &block, &spent)?),
+            )
+            .unwrap();
+
+        assert!(index.is_synced_to(block.get_hash()));
+        assert_spend_indexed(
+            &index,
+            &miner,
+            &recipient,
+            &coinbase_out,
+            &spend,
+            block.get_height(),
+        );
+    }
+
+    #[test]
+    fn disconnect_block_restores_spent_outputs() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let block = mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        let index = AddressIndex::new(blockchain.clone());
+        index.reindex();
+
+        let spent = blockchain.get_undo(block.get_hash()).unwrap();
+        blockchain
+            .get_storage()
+            .transaction(
+                &[METADATA_TREE, ADDRESS_UTXO_TREE, ADDRESS_TX_TREE],
+                &|tx| Ok(AddressIndex::disconnect_block(tx, &block, &spent)?),
+            )
+            .unwrap();
+
+        assert!(index.is_synced_to(&block.get_prev_block_hash()));
+        assert!(index.find_outpoints(&pub_key_hash(&recipient)).is_empty());
+        assert!(index
+            .find_transactions(&pub_key_hash(&recipient))
+            .is_empty());
+        let miner_outs = index.find_outpoints(&pub_key_hash(&miner));
+        assert!(miner_outs.contains(&coinbase_out));
+        assert!(!miner_outs.contains(&OutPoint::new(spend.get_id(), 1)));
+        let miner_txs = index.find_transactions(&pub_key_hash(&miner));
+        assert!(!miner_txs.contains(&(block.get_height(), spend.get_id_bytes())));
+    }
+}
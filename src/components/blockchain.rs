@@ -1,76 +1,256 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env::current_dir,
-    sync::{Arc, RwLock},
+    error::Error,
+    fmt,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex, RwLock},
 };
 
 use data_encoding::HEXLOWER;
-use sled::{transaction::TransactionResult, Db, Tree};
+use log::{error, info, warn};
 
 use super::{
-    blocks::Block,
-    transaction::{Transaction, TxOutput},
+    address_index::{AddressIndex, ADDRESS_TX_TREE, ADDRESS_UTXO_TREE},
+    blocks::{Block, BlockHeader},
+    config::GLOBAL_CONFIG,
+    proof_of_work::ProofOfWork,
+    schema::{
+        self, LATEST_BLOCK_HASH, METADATA_TREE, PRUNE_HEIGHT, TX_INDEX_BEST_BLOCK, UTXO_BEST_BLOCK,
+    },
+    storage::{KvPair, SledStorage, Storage, StorageError},
+    transaction::{OutPoint, Transaction, INCENTIVE},
+    utxoset::{UTXOEntry, UTXOSet, UTXO_TREE},
+    wallets::hash_pub_key,
 };
 
-const LATEST_BLOCK_HASH: &str = &"latest_block_hash";
 const BLOCKS_TREE: &str = "blocks";
+const HEADERS_TREE: &str = "headers";
+pub const TX_INDEX_TREE: &str = "txindex";
+const HEIGHTS_TREE: &str = "heights";
+const UNDO_TREE: &str = "undo";
+const INVALID_BLOCKS_TREE: &str = "invalid_blocks";
+const CHILDREN_TREE: &str = "children";
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+pub enum ChainError {
+    Storage(StorageError),
+    MissingInput(OutPoint),
+    InvalidTransaction(String),
+    InvalidBlock(String),
+    Corrupted(String),
+    UnsupportedSchema(u32),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Storage(e) => write!(f, "storage error: {}", e),
+            ChainError::MissingInput(outpoint) => write!(
+                f,
+                "input {}:{} is not in the UTXO set",
+                HEXLOWER.encode(outpoint.get_txid()),
+                outpoint.get_vout()
+            ),
+            ChainError::InvalidTransaction(reason) => write!(f, "invalid transaction: {}", reason),
+            ChainError::InvalidBlock(reason) => write!(f, "invalid block: {}", reason),
+            ChainError::Corrupted(reason) => write!(f, "database is inconsistent: {}", reason),
+            ChainError::UnsupportedSchema(version) => write!(
+                f,
+                "database schema version {} is newer than the supported version {}",
+                version,
+                schema::SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl Error for ChainError {}
+
+impl From<StorageError> for ChainError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Abort(e) => match e.downcast::<ChainError>() {
+                Ok(e) => *e,
+                Err(e) => ChainError::Storage(StorageError::Abort(e)),
+            },
+            e => ChainError::Storage(e),
+        }
+    }
+}
+
+// Lets storage transaction closures fail with a ChainError, which comes back
+// out of the transaction through the conversion above.
+impl From<ChainError> for StorageError {
+    fn from(e: ChainError) -> Self {
+        match e {
+            ChainError::Storage(e) => e,
+            e => StorageError::Abort(Box::new(e)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Blockchain {
     latest_blk_hash: Arc<RwLock<String>>,
-    db: Db,
+    chain_lock: Arc<Mutex<()>>,
+    storage: Arc<dyn Storage>,
 }
 
 impl Blockchain {
-    pub fn init(genesis_address: &str) -> Self {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
-
-        let data = blocks_tree.get(LATEST_BLOCK_HASH).unwrap();
-        let latest_blk_hash;
-
-        if data.is_none() {
-            let coinbase_tx = Transaction::coinbase_tx(genesis_address);
-            let block = Block::generate_genesis_block(&coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &block);
-            latest_blk_hash = String::from(block.get_hash());
-        } else {
-            latest_blk_hash = String::from_utf8(data.unwrap().to_vec()).unwrap();
-        }
+    // Opens the chain in the data directory, creating it from the network's
+    // genesis block when it does not exist yet.
+    pub fn init() -> Self {
+        Self::init_with_storage(open_data_dir())
+    }
 
-        Self {
-            latest_blk_hash: Arc::new(RwLock::new(latest_blk_hash)),
-            db,
+    // A chain kept in memory for tests, starting from the network's genesis
+    // block.
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        let storage = Arc::new(super::memory_storage::MemoryStorage::new());
+        Self::init_with_storage(storage)
+    }
+
+    pub fn init_with_storage(storage: Arc<dyn Storage>) -> Self {
+        open_schema(storage.as_ref());
+        let data = storage
+            .get(METADATA_TREE, LATEST_BLOCK_HASH.as_bytes())
+            .unwrap();
+        let latest_blk_hash = data.map(|data| String::from_utf8(data).unwrap());
+
+        let blockchain = Self {
+            latest_blk_hash: Arc::new(RwLock::new(latest_blk_hash.clone().unwrap_or_default())),
+            chain_lock: Arc::new(Mutex::new(())),
+            storage,
+        };
+
+        if latest_blk_hash.is_none() {
+            let genesis = GLOBAL_CONFIG.get_network().genesis_block();
+            blockchain
+                .update_active_chain(&[], &[genesis])
+                .unwrap_or_else(|e| panic!("Unable to store the genesis block: {}", e));
         }
+        blockchain.open_checks(true);
+        blockchain
     }
 
-    fn update_blocks_tree(block_tree: &Tree, block: &Block) {
-        let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone());
-            let _ = tx_db.insert(LATEST_BLOCK_HASH, block_hash);
+    // Creates a chain in the data directory from the headers of the active
+    // chain up to a base block and the UTXO set at that block. No bodies are
+    // stored, so the chain behaves like one pruned up to the base block.
+    pub fn init_from_snapshot(
+        headers: &[BlockHeader],
+        utxos: &[KvPair],
+    ) -> Result<Self, ChainError> {
+        Self::create_from_snapshot(open_data_dir(), headers, utxos)
+    }
+
+    // A chain kept in memory for tests, created from a snapshot.
+    #[cfg(test)]
+    pub fn temporary_from_snapshot(
+        headers: &[BlockHeader],
+        utxos: &[KvPair],
+    ) -> Result<Self, ChainError> {
+        let storage = Arc::new(super::memory_storage::MemoryStorage::new());
+        Self::create_from_snapshot(storage, headers, utxos)
+    }
+
+    fn create_from_snapshot(
+        storage: Arc<dyn Storage>,
+        headers: &[BlockHeader],
+        utxos: &[KvPair],
+    ) -> Result<Self, ChainError> {
+        open_schema(storage.as_ref());
+        if storage.contains_key(METADATA_TREE, LATEST_BLOCK_HASH.as_bytes())? {
+            panic!("A blockchain already exists in the data directory.");
+        }
+        if AddressIndex::is_enabled() {
+            panic!("ADDRESS_INDEX cannot be used on a chain without old block bodies");
+        }
+        let base = headers.last().ok_or_else(|| {
+            ChainError::InvalidBlock(String::from("the snapshot holds no headers"))
+        })?;
+
+        let trees = [METADATA_TREE, HEADERS_TREE, HEIGHTS_TREE, UTXO_TREE];
+        storage.transaction(&trees, &|tx| {
+            for header in headers {
+                let block_hash = header.get_hash().as_bytes();
+                tx.insert(HEADERS_TREE, block_hash, &header.serialize())?;
+                tx.insert(HEIGHTS_TREE, &height_key(header.get_height()), block_hash)?;
+            }
+            for (key, value) in utxos {
+                tx.insert(UTXO_TREE, key, value)?;
+            }
+
+            let tip = base.get_hash().as_bytes();
+            tx.insert(METADATA_TREE, LATEST_BLOCK_HASH.as_bytes(), tip)?;
+            tx.insert(METADATA_TREE, TX_INDEX_BEST_BLOCK.as_bytes(), tip)?;
+            tx.insert(METADATA_TREE, UTXO_BEST_BLOCK.as_bytes(), tip)?;
+            tx.insert(
+                METADATA_TREE,
+                PRUNE_HEIGHT.as_bytes(),
+                &height_key(base.get_height() + 1),
+            )?;
             Ok(())
-        });
+        })?;
+        Ok(Self::new_with_storage(storage))
     }
 
     pub fn new() -> Self {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+        Self::new_with_storage(open_data_dir())
+    }
+
+    // Opens the chain without repairing indexes or a UTXO set that lag behind
+    // the tip, so verifychain reports them instead.
+    pub fn open_without_repair() -> Self {
+        Self::open_with_storage(open_data_dir(), false)
+    }
+
+    pub fn new_with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self::open_with_storage(storage, true)
+    }
 
-        let latest_bytes = blocks_tree
-            .get(LATEST_BLOCK_HASH)
+    fn open_with_storage(storage: Arc<dyn Storage>, repair: bool) -> Self {
+        open_schema(storage.as_ref());
+        let latest_bytes = storage
+            .get(METADATA_TREE, LATEST_BLOCK_HASH.as_bytes())
             .unwrap()
             .expect("No existing blockchain found. Create one.");
 
-        let latest_blk_hash = String::from_utf8(latest_bytes.to_vec()).unwrap();
-        Self {
+        let latest_blk_hash = String::from_utf8(latest_bytes).unwrap();
+        let blockchain = Self {
             latest_blk_hash: Arc::new(RwLock::new(latest_blk_hash)),
-            db,
+            chain_lock: Arc::new(Mutex::new(())),
+            storage,
+        };
+        blockchain.open_checks(repair);
+        blockchain
+    }
+
+    fn open_checks(&self, repair: bool) {
+        let network = GLOBAL_CONFIG.get_network();
+        if self.get_block_hash_by_height(0).as_deref() != Some(network.get_genesis_hash()) {
+            panic!(
+                "The blockchain does not start with the {} genesis block. It was created for another network or by an older version.",
+                network.get_name()
+            );
+        }
+        if GLOBAL_CONFIG.get_prune_depth().is_some() && AddressIndex::is_enabled() {
+            panic!("ADDRESS_INDEX cannot be used together with PRUNE");
+        }
+        if self.is_pruned() && AddressIndex::is_enabled() {
+            panic!("ADDRESS_INDEX cannot be used on a chain without old block bodies");
+        }
+        if !repair {
+            return;
+        }
+        if let Err(e) = self.check_consistency() {
+            panic!("{}", e);
         }
     }
 
-    pub fn get_db(&self) -> &Db {
-        &self.db
+    pub fn get_storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
     }
 
     pub fn get_latest_blk_hash(&self) -> String {
@@ -82,65 +262,555 @@ impl Blockchain {
         *latest_blk_hash = String::from(new_latest_blk_hash);
     }
 
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
+    pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block, ChainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let best_height = self.get_best_height();
+        let utxo_set = UTXOSet::new(self.clone());
         for transaction in transactions {
-            if transaction.verify(self) == false {
-                panic!("Error: Invalid transaction");
-            }
+            check_transaction(
+                transaction,
+                |outpoint| utxo_set.get_entry(outpoint),
+                best_height + 1,
+            )?;
         }
 
-        let best_height = self.get_best_height();
         let block = Block::new(self.get_latest_blk_hash(), transactions, best_height + 1);
-        let block_hash = block.get_hash();
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        Self::update_blocks_tree(&blocks_tree, &block);
+        self.update_active_chain(&[], std::slice::from_ref(&block))?;
+        Ok(block)
+    }
 
-        self.set_latest_blk_hash(block_hash);
-        block
+    // Mines `count` blocks holding only a coinbase paying `address`.
+    pub fn generate(&self, count: usize, address: &str) -> Result<Vec<Block>, ChainError> {
+        (0..count)
+            .map(|_| {
+                let coinbase_tx = Transaction::coinbase_tx(address, self.get_best_height() + 1, 0);
+                self.mine_block(&[coinbase_tx])
+            })
+            .collect()
     }
 
-    pub fn add_block(&self, block: &Block) {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(_) = block_tree.get(block.get_hash()).unwrap() {
-            return;
+    pub fn add_block(&self, block: &Block) -> Result<(), ChainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        // Stored blocks off the active chain are connected again, as an
+        // earlier attempt may have failed for a reason unrelated to them.
+        if self.get_header(block.get_hash().as_bytes()).is_some() {
+            let active_hash = self.get_block_hash_by_height(block.get_height());
+            if active_hash.as_deref() == Some(block.get_hash()) || self.is_invalid(block.get_hash())
+            {
+                return Ok(());
+            }
+        } else {
+            // Our genesis block is always stored, so any other one starts a
+            // foreign chain.
+            if block.get_height() == 0 {
+                error!(
+                    "Rejected block {}: it is not our genesis block",
+                    block.get_hash()
+                );
+                return Ok(());
+            }
+            self.check_block(block)?;
+
+            let block_hash = block.get_hash().as_bytes();
+            self.storage
+                .insert(BLOCKS_TREE, block_hash, &block.serialize())?;
+            self.storage
+                .insert(HEADERS_TREE, block_hash, &block.get_header().serialize())?;
+            let child_key = format!("{}{}", block.get_prev_block_hash(), block.get_hash());
+            self.storage
+                .insert(CHILDREN_TREE, child_key.as_bytes(), &[])?;
+        }
+
+        if !self.connect_block(block)? {
+            return Ok(());
+        }
+        // Children received before their parent can be connected now.
+        let mut pending = self.get_children(block.get_hash());
+        while let Some(child) = pending.pop() {
+            match self.connect_block(&child) {
+                Ok(true) => pending.extend(self.get_children(child.get_hash())),
+                Ok(false) => {}
+                Err(e) => error!("Rejected block {}: {}", child.get_hash(), e),
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the stored `block` the tip when it extends the best chain and
+    // returns whether it did. Blocks that fail consensus checks are marked
+    // invalid so they are not connected again.
+    fn connect_block(&self, block: &Block) -> Result<bool, ChainError> {
+        if block.get_height() <= self.get_best_height() {
+            return Ok(false);
+        }
+
+        let Some((disconnected, connected)) = self.find_fork(block) else {
+            info!(
+                "Block {} is not connected to the active chain",
+                block.get_hash()
+            );
+            return Ok(false);
+        };
+        let result = self.update_active_chain(&disconnected, &connected);
+        if let Err(ChainError::InvalidTransaction(_) | ChainError::MissingInput(_)) = result {
+            self.storage
+                .insert(INVALID_BLOCKS_TREE, block.get_hash().as_bytes(), &[])?;
+        }
+        result?;
+        Ok(true)
+    }
+
+    // Stored blocks whose parent is `block_hash`.
+    fn get_children(&self, block_hash: &str) -> Vec<Block> {
+        self.storage
+            .scan_prefix(CHILDREN_TREE, block_hash.as_bytes())
+            .filter_map(|item| {
+                let (key, _) = item.unwrap();
+                self.get_block(&key[block_hash.len()..])
+            })
+            .collect()
+    }
+
+    // Context-free checks done before a received block is stored. Inputs and
+    // signatures are checked when the block is connected.
+    fn check_block(&self, block: &Block) -> Result<(), ChainError> {
+        if !ProofOfWork::new(block.clone()).validate() {
+            return Err(ChainError::InvalidBlock(format!(
+                "{} has an invalid proof of work",
+                block.get_hash()
+            )));
+        }
+
+        let parent = self.get_header(block.get_prev_block_hash().as_bytes());
+        if let Some(parent) = parent {
+            if block.get_height() != parent.get_height() + 1 {
+                return Err(ChainError::InvalidBlock(format!(
+                    "{} does not follow the height of its parent",
+                    block.get_hash()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Walks back from `block` to the active chain and returns the active
+    // blocks to disconnect (tip first) and the branch to connect (oldest
+    // first). Returns None when an ancestor of `block` is not stored, has
+    // been invalidated or is a different genesis block.
+    fn find_fork(&self, block: &Block) -> Option<(Vec<Block>, Vec<Block>)> {
+        let mut connected = vec![];
+        let mut cursor = block.clone();
+        let fork_height = loop {
+            let active_hash = self.get_block_hash_by_height(cursor.get_height());
+            if active_hash.as_deref() == Some(cursor.get_hash()) {
+                break cursor.get_height();
+            }
+            if self.is_invalid(cursor.get_hash()) {
+                info!(
+                    "Block {} builds on invalid block {}",
+                    block.get_hash(),
+                    cursor.get_hash()
+                );
+                return None;
+            }
+
+            let height = cursor.get_height();
+            if height == 0 {
+                info!(
+                    "Block {} does not descend from our genesis block",
+                    block.get_hash()
+                );
+                return None;
+            }
+            let prev_block_hash = cursor.get_prev_block_hash();
+            connected.push(cursor);
+            // The fork point only needs a header, so a branch can build on a
+            // tip whose body is not stored.
+            if self.get_block_hash_by_height(height - 1).as_deref()
+                == Some(prev_block_hash.as_str())
+            {
+                break height - 1;
+            }
+            cursor = self.get_block(prev_block_hash.as_bytes())?;
+        };
+        connected.reverse();
+
+        let first_disconnected = fork_height + 1;
+        if first_disconnected < self.get_prune_height() {
+            info!("Block {} forks below the pruned height", block.get_hash());
+            return None;
+        }
+        let disconnected = self.range(first_disconnected..=usize::MAX).rev().collect();
+        Some((disconnected, connected))
+    }
+
+    pub fn disconnect_tip(&self) -> Result<Block, ChainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let tip = self
+            .get_block(self.get_latest_blk_hash().as_bytes())
+            .expect("The latest hash is valid");
+        if tip.get_height() == 0 {
+            return Err(ChainError::InvalidBlock(String::from(
+                "the genesis block cannot be disconnected",
+            )));
+        }
+        if tip.get_height() <= self.get_prune_height() {
+            return Err(ChainError::InvalidBlock(String::from(
+                "the parent of the tip has been pruned",
+            )));
+        }
+
+        self.update_active_chain(std::slice::from_ref(&tip), &[])?;
+        Ok(tip)
+    }
+
+    pub fn invalidate_block(&self, block_hash: &str) -> Result<Vec<Block>, ChainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let block = self.get_block(block_hash.as_bytes()).ok_or_else(|| {
+            match self.get_header(block_hash.as_bytes()) {
+                Some(_) => {
+                    ChainError::InvalidBlock(format!("block {} has been pruned", block_hash))
+                }
+                None => ChainError::InvalidBlock(format!("block {} not found", block_hash)),
+            }
+        })?;
+        if block.get_height() == 0 {
+            return Err(ChainError::InvalidBlock(String::from(
+                "the genesis block cannot be invalidated",
+            )));
+        }
+        if block.get_height() <= self.get_prune_height() {
+            return Err(ChainError::InvalidBlock(format!(
+                "the parent of block {} has been pruned",
+                block_hash
+            )));
+        }
+
+        self.storage
+            .insert(INVALID_BLOCKS_TREE, block_hash.as_bytes(), &[])?;
+
+        let active_hash = self.get_block_hash_by_height(block.get_height());
+        if active_hash.as_deref() != Some(block_hash) {
+            return Ok(vec![]);
         }
 
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block.get_hash(), block.serialize());
+        let disconnected: Vec<Block> = self.range(block.get_height()..=usize::MAX).rev().collect();
+        self.update_active_chain(&disconnected, &[])?;
+        Ok(disconnected)
+    }
+
+    pub fn is_invalid(&self, block_hash: &str) -> bool {
+        self.storage
+            .contains_key(INVALID_BLOCKS_TREE, block_hash.as_bytes())
+            .unwrap()
+    }
+
+    pub fn get_undo(&self, block_hash: &str) -> Option<HashMap<OutPoint, UTXOEntry>> {
+        let undo_bytes = self
+            .storage
+            .get(UNDO_TREE, block_hash.as_bytes())
+            .unwrap()?;
+        Some(bincode::deserialize(undo_bytes.as_ref()).expect("unable to deserialize undo data"))
+    }
+
+    // Outputs spent by `block`, from its undo data or, for blocks connected
+    // before undo data was recorded, from the transactions that created them.
+    pub fn get_spent_outputs(&self, block: &Block) -> HashMap<OutPoint, UTXOEntry> {
+        if let Some(spent) = self.get_undo(block.get_hash()) {
+            return spent;
+        }
+        let mut spent = HashMap::new();
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in tx.get_vin() {
+                let outpoint = vin.get_outpoint();
+                let Some(prev_tx) = self.find_transaction(outpoint.get_txid()) else {
+                    continue;
+                };
+                if let Some(out) = prev_tx.get_vout().get(outpoint.get_vout()) {
+                    spent.insert(outpoint, UTXOEntry::new(out, 0, prev_tx.is_coinbase()));
+                }
+            }
+        }
+        spent
+    }
 
-            let latest_blk_bytes = tx_db
-                .get(self.get_latest_blk_hash())
-                .unwrap()
-                .expect("The latest hash is not valid");
+    // Disconnects `disconnected` (tip first) and connects `connected` (oldest
+    // first), writing the block bodies, tip pointer, height and transaction
+    // indexes, UTXO changes, undo data and address index in one transaction.
+    fn update_active_chain(
+        &self,
+        disconnected: &[Block],
+        connected: &[Block],
+    ) -> Result<(), ChainError> {
+        let tip_hash = match (connected.last(), disconnected.last()) {
+            (Some(tip), _) => String::from(tip.get_hash()),
+            (None, Some(fork)) => fork.get_prev_block_hash(),
+            (None, None) => return Ok(()),
+        };
 
-            let latest_block = Block::deserialize(latest_blk_bytes.as_ref());
+        // Blocks connected before undo data was recorded cannot be rolled
+        // back in place, so the new branch is checked against a replayed UTXO
+        // view and the UTXO set is rebuilt after the transaction.
+        let undo_records: Option<Vec<_>> = disconnected
+            .iter()
+            .map(|block| self.get_undo(block.get_hash()))
+            .collect();
+        let utxo_in_sync = undo_records.is_some();
+        if !utxo_in_sync && self.is_pruned() {
+            return Err(ChainError::Corrupted(String::from(
+                "undo data is missing and pruned blocks cannot be replayed",
+            )));
+        }
+        let undo_records = undo_records.unwrap_or_default();
+        if !utxo_in_sync {
+            if let Some(first) = connected.first() {
+                self.check_branch(first.get_height() - 1, connected)?;
+            }
+        }
+        let index_addresses = utxo_in_sync
+            && AddressIndex::is_enabled()
+            && AddressIndex::new(self.clone()).is_synced_to(&self.get_latest_blk_hash());
 
-            if block.get_height() > latest_block.get_height() {
-                let _ = tx_db.insert(LATEST_BLOCK_HASH, block.get_hash()).unwrap();
-                self.set_latest_blk_hash(block.get_hash());
+        let trees = [
+            METADATA_TREE,
+            BLOCKS_TREE,
+            HEADERS_TREE,
+            HEIGHTS_TREE,
+            TX_INDEX_TREE,
+            UTXO_TREE,
+            UNDO_TREE,
+            ADDRESS_UTXO_TREE,
+            ADDRESS_TX_TREE,
+        ];
+        self.storage.transaction(&trees, &|tx| {
+            for (idx, block) in disconnected.iter().enumerate() {
+                let block_hash = block.get_hash().as_bytes();
+                tx.remove(HEIGHTS_TREE, &height_key(block.get_height()))?;
+                for transaction in block.get_transactions() {
+                    tx.remove(TX_INDEX_TREE, transaction.get_id())?;
+                }
+                if let Some(spent) = undo_records.get(idx) {
+                    UTXOSet::disconnect(tx, block, spent)?;
+                    if index_addresses {
+                        AddressIndex::disconnect_block(tx, block, spent)?;
+                    }
+                    tx.remove(UNDO_TREE, block_hash)?;
+                }
             }
 
+            for block in connected {
+                let block_hash = block.get_hash().as_bytes();
+                tx.insert(BLOCKS_TREE, block_hash, &block.serialize())?;
+                tx.insert(HEADERS_TREE, block_hash, &block.get_header().serialize())?;
+                tx.insert(HEIGHTS_TREE, &height_key(block.get_height()), block_hash)?;
+                for (position, transaction) in block.get_transactions().iter().enumerate() {
+                    let location = bincode::serialize(&(block.get_hash(), position)).unwrap();
+                    tx.insert(TX_INDEX_TREE, transaction.get_id(), &location)?;
+                }
+                if utxo_in_sync {
+                    let spent = UTXOSet::update(tx, block)?;
+                    check_block_transactions(block, &spent)?;
+                    tx.insert(UNDO_TREE, block_hash, &bincode::serialize(&spent).unwrap())?;
+                    if index_addresses {
+                        AddressIndex::connect_block(tx, block, &spent)?;
+                    }
+                }
+            }
+
+            let tip = tip_hash.as_bytes();
+            tx.insert(METADATA_TREE, LATEST_BLOCK_HASH.as_bytes(), tip)?;
+            tx.insert(METADATA_TREE, TX_INDEX_BEST_BLOCK.as_bytes(), tip)?;
+            if utxo_in_sync {
+                tx.insert(METADATA_TREE, UTXO_BEST_BLOCK.as_bytes(), tip)?;
+            }
             Ok(())
-        });
+        })?;
+        self.set_latest_blk_hash(&tip_hash);
+
+        // Until the rebuild finishes the UTXO marker still names the old tip.
+        if !utxo_in_sync {
+            info!(
+                "Chain moved to {} without undo data, rebuilding UTXO set",
+                tip_hash
+            );
+            UTXOSet::new(self.clone()).reindex();
+        } else if AddressIndex::is_enabled() && !index_addresses {
+            AddressIndex::new(self.clone()).reindex();
+        }
+
+        if let Some(depth) = GLOBAL_CONFIG.get_prune_depth() {
+            self.prune_blocks(depth)?;
+        }
+        Ok(())
     }
 
-    pub fn get_best_height(&self) -> usize {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let latest_blk_bytes = block_tree
-            .get(self.get_latest_blk_hash())
+    // Checks a branch forking off the active chain at `fork_height` against a
+    // UTXO view replayed from the genesis block. Only used when the stored
+    // UTXO set cannot be rolled back to the fork.
+    fn check_branch(&self, fork_height: usize, connected: &[Block]) -> Result<(), ChainError> {
+        let mut utxo = HashMap::new();
+        for block in self.range(0..=fork_height) {
+            connect_in_memory(&mut utxo, &block)?;
+        }
+        for block in connected {
+            let spent = connect_in_memory(&mut utxo, block)?;
+            check_block_transactions(block, &spent)?;
+        }
+        Ok(())
+    }
+
+    // Deletes the bodies and undo data of active blocks more than `depth`
+    // blocks below the tip. Headers and index entries are kept.
+    fn prune_blocks(&self, depth: usize) -> Result<(), ChainError> {
+        let prune_height = self.get_prune_height();
+        let target_height = (self.get_best_height() + 1).saturating_sub(depth);
+        if target_height <= prune_height {
+            return Ok(());
+        }
+
+        let block_hashes: Vec<String> = (prune_height..target_height)
+            .filter_map(|height| self.get_block_hash_by_height(height))
+            .collect();
+        self.storage
+            .transaction(&[METADATA_TREE, BLOCKS_TREE, UNDO_TREE], &|tx| {
+                for block_hash in &block_hashes {
+                    tx.remove(BLOCKS_TREE, block_hash.as_bytes())?;
+                    tx.remove(UNDO_TREE, block_hash.as_bytes())?;
+                }
+                tx.insert(
+                    METADATA_TREE,
+                    PRUNE_HEIGHT.as_bytes(),
+                    &height_key(target_height),
+                )?;
+                Ok(())
+            })?;
+        info!("Pruned block bodies below height {}", target_height);
+        Ok(())
+    }
+
+    // Lowest height of the active chain whose block body is still stored.
+    pub fn get_prune_height(&self) -> usize {
+        match self
+            .storage
+            .get(METADATA_TREE, PRUNE_HEIGHT.as_bytes())
             .unwrap()
+        {
+            Some(height) => u64::from_be_bytes(height.as_slice().try_into().unwrap()) as usize,
+            None => 0,
+        }
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.get_prune_height() > 0
+    }
+
+    pub fn set_utxo_best_block(&self, block_hash: &str) {
+        self.storage
+            .insert(
+                METADATA_TREE,
+                UTXO_BEST_BLOCK.as_bytes(),
+                block_hash.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    // Repairs indexes and the UTXO set that lag behind the tip, which only
+    // happens for databases written by older versions or an interrupted
+    // reorganization. A missing tip block cannot be repaired.
+    pub fn check_consistency(&self) -> Result<(), ChainError> {
+        if !self.indexes_in_sync()? {
+            warn!("Height and transaction indexes diverge from the tip, rebuilding");
+            if self.is_pruned() {
+                return Err(ChainError::Corrupted(String::from(
+                    "indexes diverge from the tip and pruned blocks cannot be replayed",
+                )));
+            }
+            self.reindex_indexes();
+        }
+
+        // Databases written before the marker existed also used an older
+        // Chainstate layout, so a missing marker rebuilds the set as well.
+        if !self.utxo_in_sync()? {
+            warn!("UTXO set diverges from the tip, rebuilding");
+            if self.is_pruned() {
+                return Err(ChainError::Corrupted(String::from(
+                    "UTXO set diverges from the tip and pruned blocks cannot be replayed",
+                )));
+            }
+            UTXOSet::new(self.clone()).reindex();
+        }
+        Ok(())
+    }
+
+    // Whether the height and transaction indexes end at the tip.
+    pub fn indexes_in_sync(&self) -> Result<bool, ChainError> {
+        let latest_blk_hash = self.get_latest_blk_hash();
+        let tip = self.get_header(latest_blk_hash.as_bytes()).ok_or_else(|| {
+            ChainError::Corrupted(format!("tip block {} is missing", latest_blk_hash))
+        })?;
+
+        let best_block = self
+            .storage
+            .get(METADATA_TREE, TX_INDEX_BEST_BLOCK.as_bytes())?;
+        let height_hash = self.get_block_hash_by_height(tip.get_height());
+        Ok(best_block.as_deref() == Some(latest_blk_hash.as_bytes())
+            && height_hash.as_deref() == Some(latest_blk_hash.as_str()))
+    }
+
+    pub fn utxo_in_sync(&self) -> Result<bool, ChainError> {
+        let utxo_best_block = self
+            .storage
+            .get(METADATA_TREE, UTXO_BEST_BLOCK.as_bytes())?;
+        Ok(utxo_best_block.as_deref() == Some(self.get_latest_blk_hash().as_bytes()))
+    }
+
+    pub fn reindex_indexes(&self) {
+        if self.is_pruned() {
+            panic!("Unable to rebuild the indexes: old block bodies have been pruned");
+        }
+        self.storage.clear(HEIGHTS_TREE).unwrap();
+        self.storage.clear(TX_INDEX_TREE).unwrap();
+
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            self.storage
+                .insert(
+                    HEIGHTS_TREE,
+                    &height_key(block.get_height()),
+                    block.get_hash().as_bytes(),
+                )
+                .unwrap();
+            for (position, tx) in block.get_transactions().iter().enumerate() {
+                let location = bincode::serialize(&(block.get_hash(), position)).unwrap();
+                self.storage
+                    .insert(TX_INDEX_TREE, tx.get_id(), &location)
+                    .unwrap();
+            }
+        }
+        let latest_blk_hash = self.get_latest_blk_hash();
+        self.storage
+            .insert(
+                METADATA_TREE,
+                TX_INDEX_BEST_BLOCK.as_bytes(),
+                latest_blk_hash.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    pub fn get_best_height(&self) -> usize {
+        let latest_header = self
+            .get_header(self.get_latest_blk_hash().as_bytes())
             .expect("The latest hash is valid");
-        let latest_block = Block::deserialize(latest_blk_bytes.as_ref());
-        latest_block.get_height()
+        latest_header.get_height()
     }
 
     pub fn iterator(&self) -> BlockchainIterator {
-        BlockchainIterator::new(self.get_latest_blk_hash(), self.db.clone())
+        BlockchainIterator::new(self.get_latest_blk_hash(), self.storage.clone())
     }
 
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TxOutput>> {
-        let mut utxo: HashMap<String, Vec<TxOutput>> = HashMap::new();
-        let mut stxo: HashMap<String, Vec<usize>> = HashMap::new();
+    pub fn find_utxo(&self) -> HashMap<OutPoint, UTXOEntry> {
+        let mut utxo: HashMap<OutPoint, UTXOEntry> = HashMap::new();
+        let mut stxo: HashSet<OutPoint> = HashSet::new();
 
         let mut iterator = self.iterator();
 
@@ -151,22 +821,15 @@ impl Blockchain {
             }
 
             let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
+            for tx in block.get_transactions().iter().rev() {
                 for (idx, out) in tx.get_vout().iter().enumerate() {
-                    if let Some(outs) = stxo.get(txid_hex.as_str()) {
-                        for stxo_out_idx in outs {
-                            if idx.eq(stxo_out_idx) {
-                                continue 'outer;
-                            }
-                        }
+                    let outpoint = OutPoint::new(tx.get_id(), idx);
+                    if stxo.contains(&outpoint) {
+                        continue;
                     }
 
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                    }
+                    let entry = UTXOEntry::new(out, block.get_height(), tx.is_coinbase());
+                    utxo.insert(outpoint, entry);
                 }
 
                 if tx.is_coinbase() {
@@ -174,88 +837,664 @@ impl Blockchain {
                 }
 
                 for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if stxo.contains_key(txid_hex.as_str()) {
-                        stxo.get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(txin.get_vout());
-                    } else {
-                        stxo.insert(txid_hex, vec![txin.get_vout()]);
-                    }
+                    stxo.insert(txin.get_outpoint());
                 }
             }
         }
         utxo
     }
 
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+    // Hashes of every public key that received an output or signed an input
+    // in a stored block, plus the owners of unspent outputs. Outputs spent in
+    // pruned blocks are missed.
+    pub fn find_used_pub_key_hashes(&self) -> HashSet<Vec<u8>> {
+        let mut used = HashSet::new();
         let mut iterator = self.iterator();
-
-        loop {
-            let option = iterator.next();
-
-            if option.is_none() {
-                break;
-            }
-
-            let block = option.unwrap();
-            for transaction in block.get_transactions() {
-                if txid.eq(transaction.get_id()) {
-                    return Some(transaction.clone());
+        while let Some(block) = iterator.next() {
+            for tx in block.get_transactions() {
+                for out in tx.get_vout() {
+                    used.insert(out.get_pub_key_hash().to_vec());
+                }
+                if !tx.is_coinbase() {
+                    for vin in tx.get_vin() {
+                        used.insert(hash_pub_key(vin.get_pub_key()));
+                    }
                 }
             }
         }
-        None
+        for item in self.storage.iter(UTXO_TREE) {
+            let (_, value) = item.unwrap();
+            let entry: UTXOEntry =
+                bincode::deserialize(&value).expect("unable to deserialize UTXOEntry");
+            used.insert(entry.get_pub_key_hash().to_vec());
+        }
+        used
+    }
+
+    pub fn find_transaction_location(&self, txid: &[u8]) -> Option<(String, usize)> {
+        let location = self.storage.get(TX_INDEX_TREE, txid).unwrap()?;
+        Some(bincode::deserialize(location.as_slice()).expect("unable to deserialize tx location"))
     }
 
+    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+        let (block_hash, position) = self.find_transaction_location(txid)?;
+        let block = self.get_block(block_hash.as_bytes())?;
+        block.get_transactions().get(position).cloned()
+    }
+
+    // Hashes of the active blocks this node can still serve, oldest first.
     pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let mut iterator = self.iterator();
-        let mut blocks = vec![];
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            let block = option.unwrap();
-            blocks.push(block.get_hash_bytes());
+        (self.get_prune_height()..=self.get_best_height())
+            .filter_map(|height| self.get_block_hash_by_height(height))
+            .map(String::into_bytes)
+            .collect()
+    }
+
+    pub fn get_block_hash_by_height(&self, height: usize) -> Option<String> {
+        let block_hash = self
+            .storage
+            .get(HEIGHTS_TREE, &height_key(height))
+            .unwrap()?;
+        Some(String::from_utf8(block_hash).unwrap())
+    }
+
+    pub fn get_block_by_height(&self, height: usize) -> Option<Block> {
+        let block_hash = self.get_block_hash_by_height(height)?;
+        self.get_block(block_hash.as_bytes())
+    }
+
+    pub fn range(&self, heights: RangeInclusive<usize>) -> BlockRangeIterator {
+        let best_height = self.get_best_height();
+        let end = (*heights.end()).min(best_height);
+        BlockRangeIterator {
+            blockchain: self.clone(),
+            heights: *heights.start()..=end,
+        }
+    }
+
+    // Headers outlive pruned bodies. Blocks stored before the headers tree
+    // existed fall back to their body.
+    pub fn get_header(&self, block_hash: &[u8]) -> Option<BlockHeader> {
+        if let Some(header_bytes) = self.storage.get(HEADERS_TREE, block_hash).unwrap() {
+            return Some(BlockHeader::deserialize(header_bytes.as_slice()));
         }
-        blocks
+        self.get_block(block_hash).map(|block| block.get_header())
     }
 
     pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(block_bytes) = block_tree.get(block_hash).unwrap() {
-            let block = Block::deserialize(block_bytes.as_ref());
+        if let Some(block_bytes) = self.storage.get(BLOCKS_TREE, block_hash).unwrap() {
+            let block = Block::deserialize(block_bytes.as_slice());
             return Some(block);
         }
         None
     }
 }
 
-#[derive(Debug)]
+// Checks the amounts and signatures of `transaction` against the outputs it
+// spends, returned by `prev_out`, for a block at `spend_height`. Returns its
+// fee.
+pub fn check_transaction(
+    transaction: &Transaction,
+    prev_out: impl Fn(&OutPoint) -> Option<UTXOEntry>,
+    spend_height: usize,
+) -> Result<i32, ChainError> {
+    let txid_hex = HEXLOWER.encode(transaction.get_id());
+    let invalid = |reason: &str| {
+        Err(ChainError::InvalidTransaction(format!(
+            "{} {}",
+            txid_hex, reason
+        )))
+    };
+    if transaction.get_vout().iter().any(|out| out.get_value() < 0) {
+        return invalid("has a negative output");
+    }
+    if transaction.is_coinbase() {
+        return Ok(0);
+    }
+    if transaction.has_duplicate_inputs() {
+        return invalid("spends an output twice");
+    }
+
+    let mut input_value = 0;
+    for vin in transaction.get_vin() {
+        let outpoint = vin.get_outpoint();
+        let entry =
+            prev_out(&outpoint).ok_or_else(|| ChainError::MissingInput(outpoint.clone()))?;
+        if !entry.is_mature(spend_height) {
+            return invalid("spends an immature coinbase output");
+        }
+        input_value += entry.get_value();
+    }
+    if !transaction.verify_with(&prev_out) {
+        return invalid("has an invalid signature");
+    }
+    let output_value: i32 = transaction
+        .get_vout()
+        .iter()
+        .map(|out| out.get_value())
+        .sum();
+    let fee = input_value - output_value;
+    if fee < 0 {
+        return invalid("pays out more than its inputs");
+    }
+    Ok(fee)
+}
+
+// Checks the transactions of a block whose spent outputs are `spent`. The
+// coinbase may claim the block reward and the fees of the block.
+fn check_block_transactions(
+    block: &Block,
+    spent: &HashMap<OutPoint, UTXOEntry>,
+) -> Result<(), ChainError> {
+    let mut fees = 0;
+    for transaction in block.get_transactions() {
+        fees += check_transaction(
+            transaction,
+            |outpoint| spent.get(outpoint).cloned(),
+            block.get_height(),
+        )
+        .map_err(|e| match e {
+            ChainError::InvalidTransaction(reason) => {
+                ChainError::InvalidTransaction(format!("{} in block {}", reason, block.get_hash()))
+            }
+            e => e,
+        })?;
+    }
+
+    let reward: i32 = block
+        .get_transactions()
+        .iter()
+        .filter(|transaction| transaction.is_coinbase())
+        .flat_map(|transaction| transaction.get_vout())
+        .map(|out| out.get_value())
+        .sum();
+    if reward > INCENTIVE + fees {
+        return Err(ChainError::InvalidTransaction(format!(
+            "coinbase of block {} pays {}, more than the reward and fees of {}",
+            block.get_hash(),
+            reward,
+            INCENTIVE + fees
+        )));
+    }
+    Ok(())
+}
+
+// Applies `block` to an in-memory UTXO set like `UTXOSet::update` and returns
+// the outputs it spent.
+fn connect_in_memory(
+    utxo: &mut HashMap<OutPoint, UTXOEntry>,
+    block: &Block,
+) -> Result<HashMap<OutPoint, UTXOEntry>, ChainError> {
+    let mut spent = HashMap::new();
+    for tx in block.get_transactions() {
+        if !tx.is_coinbase() {
+            for vin in tx.get_vin() {
+                let outpoint = vin.get_outpoint();
+                let entry = utxo
+                    .remove(&outpoint)
+                    .ok_or_else(|| ChainError::MissingInput(outpoint.clone()))?;
+                spent.insert(outpoint, entry);
+            }
+        }
+
+        for (idx, out) in tx.get_vout().iter().enumerate() {
+            let entry = UTXOEntry::new(out, block.get_height(), tx.is_coinbase());
+            utxo.insert(OutPoint::new(tx.get_id(), idx), entry);
+        }
+    }
+    Ok(spent)
+}
+
+fn open_schema(storage: &dyn Storage) {
+    if let Err(e) = schema::migrate(storage) {
+        panic!("Unable to open the database: {}", e);
+    }
+}
+
+fn open_data_dir() -> Arc<dyn Storage> {
+    let path = current_dir()
+        .unwrap()
+        .join(GLOBAL_CONFIG.get_network().get_data_dir());
+    Arc::new(SledStorage::open(&path).unwrap())
+}
+
 pub struct BlockchainIterator {
-    db: Db,
+    storage: Arc<dyn Storage>,
     current_hash: String,
 }
 
 impl BlockchainIterator {
-    fn new(latest_blk_hash: String, db: Db) -> Self {
+    fn new(latest_blk_hash: String, storage: Arc<dyn Storage>) -> Self {
         Self {
             current_hash: latest_blk_hash,
-            db,
+            storage,
         }
     }
 
     pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let data = block_tree.get(self.current_hash.clone()).unwrap();
+        let data = self
+            .storage
+            .get(BLOCKS_TREE, self.current_hash.as_bytes())
+            .unwrap()?;
 
-        if data.is_none() {
-            return None;
+        let block = Block::deserialize(data.as_slice());
+        self.current_hash = block.get_prev_block_hash();
+        Some(block)
+    }
+}
+
+pub struct BlockRangeIterator {
+    blockchain: Blockchain,
+    heights: RangeInclusive<usize>,
+}
+
+impl Iterator for BlockRangeIterator {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let height = self.heights.next()?;
+        self.blockchain.get_block_by_height(height)
+    }
+}
+
+impl DoubleEndedIterator for BlockRangeIterator {
+    fn next_back(&mut self) -> Option<Block> {
+        let height = self.heights.next_back()?;
+        self.blockchain.get_block_by_height(height)
+    }
+}
+
+fn height_key(height: usize) -> Vec<u8> {
+    (height as u64).to_be_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        test_fixtures::{mature, mine, spend_coinbase, test_wallet},
+        transaction::TxOutput,
+        wallets::Wallet,
+    };
+
+    fn coinbase_block(prev_block_hash: &str, miner: &Wallet, height: usize) -> Block {
+        let coinbase = Transaction::coinbase_tx(&miner.get_address(), height, 0);
+        Block::new(String::from(prev_block_hash), &[coinbase], height)
+    }
+
+    fn coinbase_id(block: &Block) -> &[u8] {
+        block.get_transactions()[0].get_id()
+    }
+
+    // A chain whose miner owns the mature output of 10 that is returned.
+    fn chain_with_mature_output(miner: &Wallet) -> (Blockchain, OutPoint) {
+        let blockchain = Blockchain::temporary();
+        let block = mine(&blockchain, miner, &[]);
+        mature(&blockchain, miner);
+        (blockchain, OutPoint::new(coinbase_id(&block), 0))
+    }
+
+    fn spend(
+        blockchain: &Blockchain,
+        miner: &Wallet,
+        inputs: &[OutPoint],
+        values: &[i32],
+    ) -> Transaction {
+        let outputs = values
+            .iter()
+            .map(|value| TxOutput::new(*value, &miner.get_address()))
+            .collect();
+        Transaction::spend(blockchain, miner, inputs, outputs)
+    }
+
+    fn check(blockchain: &Blockchain, transaction: &Transaction) -> Result<i32, ChainError> {
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        check_transaction(
+            transaction,
+            |outpoint| utxo_set.get_entry(outpoint),
+            blockchain.get_best_height() + 1,
+        )
+    }
+
+    #[test]
+    fn generate_mines_blocks_paying_the_address() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let blocks = blockchain.generate(3, &miner.get_address()).unwrap();
+
+        assert_eq!(blockchain.get_best_height(), 3);
+        assert_eq!(blockchain.get_latest_blk_hash(), blocks[2].get_hash());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        for (height, block) in (1..).zip(&blocks) {
+            assert_eq!(block.get_height(), height);
+            assert_eq!(block.get_transactions().len(), 1);
+            let entry = utxo_set
+                .get_entry(&OutPoint::new(coinbase_id(block), 0))
+                .unwrap();
+            assert!(entry.is_coinbase());
+            assert_eq!(entry.get_value(), INCENTIVE);
         }
+        assert!(blockchain
+            .generate(0, &miner.get_address())
+            .unwrap()
+            .is_empty());
+    }
 
-        let block = Block::deserialize(data.unwrap().to_vec().as_slice());
-        self.current_hash = block.get_prev_block_hash().clone();
-        Some(block)
+    #[test]
+    fn indexes_transactions_of_mined_blocks() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        let block = mine(&blockchain, &miner, &[]);
+
+        assert_eq!(
+            blockchain.find_transaction_location(coinbase_id(&block)),
+            Some((String::from(block.get_hash()), 0))
+        );
+        let genesis = blockchain.get_block(genesis_hash.as_bytes()).unwrap();
+        let found = blockchain.find_transaction(coinbase_id(&genesis)).unwrap();
+        assert_eq!(found.get_id(), coinbase_id(&genesis));
+        assert!(blockchain.find_transaction(&[0; 32]).is_none());
+    }
+
+    #[test]
+    fn reorg_reindexes_transactions() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        let stale = mine(&blockchain, &miner, &[]);
+
+        // Coinbases only differ by address and height, so the fork pays
+        // another miner.
+        let fork_miner = test_wallet(1);
+        let fork = coinbase_block(&genesis_hash, &fork_miner, 1);
+        blockchain.add_block(&fork).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), stale.get_hash());
+        assert!(blockchain.find_transaction(coinbase_id(&fork)).is_none());
+
+        let tip = coinbase_block(fork.get_hash(), &fork_miner, 2);
+        blockchain.add_block(&tip).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), tip.get_hash());
+        assert!(blockchain.find_transaction(coinbase_id(&stale)).is_none());
+        assert!(blockchain.find_transaction(coinbase_id(&fork)).is_some());
+        assert_eq!(
+            blockchain.find_transaction_location(coinbase_id(&tip)),
+            Some((String::from(tip.get_hash()), 0))
+        );
+    }
+
+    #[test]
+    fn reorg_moves_the_height_index() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        let stale = mine(&blockchain, &miner, &[]);
+        assert_eq!(
+            blockchain.get_block_hash_by_height(1).as_deref(),
+            Some(stale.get_hash())
+        );
+
+        let fork_miner = test_wallet(1);
+        let fork = coinbase_block(&genesis_hash, &fork_miner, 1);
+        let tip = coinbase_block(fork.get_hash(), &fork_miner, 2);
+        blockchain.add_block(&fork).unwrap();
+        blockchain.add_block(&tip).unwrap();
+
+        assert_eq!(blockchain.get_best_height(), 2);
+        assert_eq!(
+            blockchain.get_block_hash_by_height(1).as_deref(),
+            Some(fork.get_hash())
+        );
+        assert_eq!(
+            blockchain.get_block_by_height(2).unwrap().get_hash(),
+            tip.get_hash()
+        );
+        let heights: Vec<usize> = blockchain.range(0..=5).map(|b| b.get_height()).collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+        // The stale block stays stored but leaves the active chain.
+        assert!(blockchain.get_block(stale.get_hash().as_bytes()).is_some());
+    }
+
+    #[test]
+    fn waits_for_missing_ancestors() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+
+        let parent = coinbase_block(&genesis_hash, &miner, 1);
+        let child = coinbase_block(parent.get_hash(), &miner, 2);
+        blockchain.add_block(&child).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), genesis_hash);
+        assert!(blockchain.get_block_hash_by_height(2).is_none());
+
+        blockchain.add_block(&parent).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), child.get_hash());
+        assert_eq!(
+            blockchain.get_block_hash_by_height(1).as_deref(),
+            Some(parent.get_hash())
+        );
+    }
+
+    #[test]
+    fn retries_stored_blocks_off_the_active_chain() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        mine(&blockchain, &miner, &[]);
+        let fork = coinbase_block(&genesis_hash, &test_wallet(1), 1);
+        blockchain.add_block(&fork).unwrap();
+        assert_ne!(blockchain.get_latest_blk_hash(), fork.get_hash());
+
+        blockchain.disconnect_tip().unwrap();
+        blockchain.add_block(&fork).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), fork.get_hash());
+    }
+
+    #[test]
+    fn rejects_a_foreign_genesis_block() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+
+        let coinbase = Transaction::coinbase_tx(&miner.get_address(), 0, 0);
+        let foreign = Block::generate_genesis_block(&coinbase, 0, 0);
+        blockchain.add_block(&foreign).unwrap();
+        assert!(blockchain
+            .get_block(foreign.get_hash().as_bytes())
+            .is_none());
+
+        let child = coinbase_block(foreign.get_hash(), &miner, 1);
+        blockchain.add_block(&child).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), genesis_hash);
+        assert_eq!(blockchain.get_block_hash_by_height(0), Some(genesis_hash));
+    }
+
+    #[test]
+    fn check_transaction_returns_the_fee() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        let transaction = spend(&blockchain, &miner, &[output], &[4, 3]);
+        assert_eq!(check(&blockchain, &transaction).unwrap(), 3);
+    }
+
+    #[test]
+    fn check_transaction_rejects_invalid_amounts() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        for values in [[11, 0], [-5, 15]] {
+            let transaction = spend(&blockchain, &miner, std::slice::from_ref(&output), &values);
+            assert!(matches!(
+                check(&blockchain, &transaction),
+                Err(ChainError::InvalidTransaction(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn check_transaction_rejects_bad_inputs() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        let twice = spend(&blockchain, &miner, &[output.clone(), output], &[15]);
+        assert!(matches!(
+            check(&blockchain, &twice),
+            Err(ChainError::InvalidTransaction(_))
+        ));
+
+        let block = mine(&blockchain, &miner, &[]);
+        let immature = OutPoint::new(coinbase_id(&block), 0);
+        let transaction = spend(&blockchain, &miner, std::slice::from_ref(&immature), &[10]);
+        assert!(matches!(
+            check(&blockchain, &transaction),
+            Err(ChainError::InvalidTransaction(_))
+        ));
+
+        let result = check_transaction(&transaction, |_| None, 100);
+        assert!(matches!(result, Err(ChainError::MissingInput(outpoint)) if outpoint == immature));
+    }
+
+    #[test]
+    fn coinbase_may_claim_the_reward_and_fees() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        let transaction = spend(&blockchain, &miner, std::slice::from_ref(&output), &[7]);
+        let spent = HashMap::from([(
+            output.clone(),
+            UTXOSet::new(blockchain.clone()).get_entry(&output).unwrap(),
+        )]);
+        let coinbase = || Transaction::coinbase_tx(&miner.get_address(), 20, 0);
+
+        let block = Block::new(String::new(), &[coinbase(), transaction], 20);
+        assert!(check_block_transactions(&block, &spent).is_ok());
+        let block = Block::new(String::new(), &[coinbase(), coinbase()], 20);
+        assert!(check_block_transactions(&block, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_a_received_block_paying_more_than_its_inputs() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        let tip_hash = blockchain.get_latest_blk_hash();
+        let transaction = spend(&blockchain, &miner, std::slice::from_ref(&output), &[11]);
+        let height = blockchain.get_best_height() + 1;
+        let coinbase = Transaction::coinbase_tx(&miner.get_address(), height, 0);
+        let block = Block::new(tip_hash.clone(), &[coinbase, transaction], height);
+
+        assert!(matches!(
+            blockchain.add_block(&block),
+            Err(ChainError::InvalidTransaction(_))
+        ));
+        assert_eq!(blockchain.get_latest_blk_hash(), tip_hash);
+        assert!(UTXOSet::new(blockchain.clone())
+            .get_entry(&output)
+            .is_some());
+        assert!(blockchain.is_invalid(block.get_hash()));
+        blockchain.add_block(&block).unwrap();
+    }
+
+    #[test]
+    fn disconnect_tip_restores_spent_outputs() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, transaction) = spend_coinbase(&blockchain, &miner, &recipient);
+        let tip_hash = blockchain.get_latest_blk_hash();
+        let block = mine(&blockchain, &miner, std::slice::from_ref(&transaction));
+
+        let disconnected = blockchain.disconnect_tip().unwrap();
+        assert_eq!(disconnected.get_hash(), block.get_hash());
+        assert_eq!(blockchain.get_latest_blk_hash(), tip_hash);
+        assert!(blockchain.get_undo(block.get_hash()).is_none());
+        assert!(blockchain.find_transaction(transaction.get_id()).is_none());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert!(utxo_set.get_entry(&coinbase_out).is_some());
+        assert!(utxo_set
+            .get_entry(&OutPoint::new(transaction.get_id(), 0))
+            .is_none());
+    }
+
+    #[test]
+    fn invalidated_blocks_are_not_reconnected() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        let block = mine(&blockchain, &miner, &[]);
+        let tip = mine(&blockchain, &miner, &[]);
+
+        let disconnected = blockchain.invalidate_block(block.get_hash()).unwrap();
+        let hashes: Vec<&str> = disconnected.iter().map(|b| b.get_hash()).collect();
+        assert_eq!(hashes, vec![tip.get_hash(), block.get_hash()]);
+        assert_eq!(blockchain.get_latest_blk_hash(), genesis_hash);
+        assert!(blockchain.is_invalid(block.get_hash()));
+        assert!(UTXOSet::new(blockchain.clone())
+            .get_entry(&OutPoint::new(coinbase_id(&block), 0))
+            .is_none());
+
+        let child = coinbase_block(tip.get_hash(), &miner, 3);
+        blockchain.add_block(&child).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), genesis_hash);
+        assert!(blockchain.disconnect_tip().is_err());
+    }
+
+    #[test]
+    fn checks_branches_connected_without_undo_data() {
+        let miner = test_wallet(0);
+        let (blockchain, output) = chain_with_mature_output(&miner);
+        let fork_hash = blockchain.get_latest_blk_hash();
+        let height = blockchain.get_best_height() + 1;
+        let stale = mine(&blockchain, &miner, &[]);
+        blockchain
+            .get_storage()
+            .remove(UNDO_TREE, stale.get_hash().as_bytes())
+            .unwrap();
+
+        let branch = |values: &[i32]| {
+            let transaction = spend(&blockchain, &miner, std::slice::from_ref(&output), values);
+            let coinbase = Transaction::coinbase_tx(&miner.get_address(), height, 0);
+            let block = Block::new(fork_hash.clone(), &[coinbase, transaction], height);
+            let child = coinbase_block(block.get_hash(), &miner, height + 1);
+            (block, child)
+        };
+
+        let (invalid, child) = branch(&[11]);
+        blockchain.add_block(&invalid).unwrap();
+        assert!(matches!(
+            blockchain.add_block(&child),
+            Err(ChainError::InvalidTransaction(_))
+        ));
+        assert_eq!(blockchain.get_latest_blk_hash(), stale.get_hash());
+
+        let (valid, child) = branch(&[7]);
+        blockchain.add_block(&valid).unwrap();
+        blockchain.add_block(&child).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), child.get_hash());
+        assert!(UTXOSet::new(blockchain.clone())
+            .get_entry(&output)
+            .is_none());
+    }
+
+    #[test]
+    fn prune_blocks_keeps_headers_and_outputs() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let pruned = mine(&blockchain, &miner, &[]);
+        for _ in 0..4 {
+            mine(&blockchain, &miner, &[]);
+        }
+        blockchain.prune_blocks(2).unwrap();
+
+        assert_eq!(blockchain.get_prune_height(), 4);
+        assert!(blockchain.is_pruned());
+        assert!(blockchain.get_block(pruned.get_hash().as_bytes()).is_none());
+        assert!(blockchain.get_undo(pruned.get_hash()).is_none());
+        let header = blockchain.get_header(pruned.get_hash().as_bytes()).unwrap();
+        assert_eq!(header.get_height(), 1);
+        assert!(blockchain.get_block_by_height(4).is_some());
+        assert!(UTXOSet::new(blockchain.clone())
+            .get_entry(&OutPoint::new(coinbase_id(&pruned), 0))
+            .is_some());
+
+        assert!(blockchain.invalidate_block(pruned.get_hash()).is_err());
+        assert_eq!(blockchain.disconnect_tip().unwrap().get_height(), 5);
+        assert!(blockchain.disconnect_tip().is_err());
+        assert_eq!(blockchain.get_best_height(), 4);
     }
 }
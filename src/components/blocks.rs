@@ -1,10 +1,47 @@
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
-use crate::components::{proof_of_work::ProofOfWork, transaction::Transaction};
+use crate::components::{
+    config::GLOBAL_CONFIG, proof_of_work::ProofOfWork, transaction::Transaction,
+};
 
 use super::helpers::{current_timestamp, sha256_digest};
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    timestamp: i64,
+    pre_block_hash: String,
+    hash: String,
+    nonce: i64,
+    height: usize,
+}
+
+impl BlockHeader {
+    pub fn deserialize(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap().to_vec()
+    }
+
+    pub fn get_prev_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    pub fn get_hash(&self) -> &str {
+        self.hash.as_str()
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     timestamp: i64,
@@ -17,16 +54,33 @@ pub struct Block {
 
 impl Block {
     pub fn new(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Self {
+        let target_bits = GLOBAL_CONFIG.get_network().get_target_bits();
+        Self::mine(
+            pre_block_hash,
+            transactions,
+            height,
+            current_timestamp(),
+            target_bits,
+        )
+    }
+
+    fn mine(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        timestamp: i64,
+        target_bits: i32,
+    ) -> Self {
         let mut block = Self {
-            timestamp: current_timestamp(),
+            timestamp,
             pre_block_hash,
             hash: String::new(),
             transactions: transactions.to_vec(),
             nonce: 0,
-            height: height,
+            height,
         };
 
-        let pow = ProofOfWork::new(block.clone());
+        let pow = ProofOfWork::with_target_bits(block.clone(), target_bits);
         let (nonce, hash) = pow.run();
         block.nonce = nonce;
         block.hash = hash;
@@ -42,9 +96,29 @@ impl Block {
         bincode::serialize(self).unwrap().to_vec()
     }
 
-    pub fn generate_genesis_block(transaction: &Transaction) -> Self {
+    pub fn generate_genesis_block(
+        transaction: &Transaction,
+        timestamp: i64,
+        target_bits: i32,
+    ) -> Self {
         let transactions = vec![transaction.clone()];
-        Self::new(String::from("None"), &transactions, 0)
+        Self::mine(
+            String::from("None"),
+            &transactions,
+            0,
+            timestamp,
+            target_bits,
+        )
+    }
+
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            pre_block_hash: self.pre_block_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+        }
     }
 
     pub fn hash_transactions(&self) -> Vec<u8> {
@@ -75,6 +149,10 @@ impl Block {
         self.timestamp
     }
 
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
     pub fn get_height(&self) -> usize {
         self.height
     }
@@ -0,0 +1,224 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::info;
+
+use super::{blockchain::Blockchain, blocks::Block, config::GLOBAL_CONFIG};
+
+// File layout: MAGIC, a version byte and a flags byte, followed by the active
+// chain from genesis to tip. Each block is a u32 big-endian length and the
+// bincode encoded block. With FLAG_GZIP set, everything after the flags byte
+// is a gzip stream.
+const MAGIC: &[u8; 4] = b"MBCX";
+const FORMAT_VERSION: u8 = 1;
+const FLAG_GZIP: u8 = 1;
+
+// Guards against allocating for a corrupt length prefix.
+const MAX_RECORD_SIZE: usize = 32 * 1024 * 1024;
+
+pub fn export_chain(
+    blockchain: &Blockchain,
+    path: &Path,
+    compress: bool,
+) -> Result<usize, Box<dyn Error>> {
+    if blockchain.is_pruned() {
+        return Err("cannot export a pruned chain".into());
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&[FORMAT_VERSION, if compress { FLAG_GZIP } else { 0 }])?;
+
+    let best_height = blockchain.get_best_height();
+    if compress {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        write_blocks(blockchain, best_height, &mut encoder)?;
+        encoder.finish()?.flush()?;
+    } else {
+        write_blocks(blockchain, best_height, &mut file)?;
+        file.flush()?;
+    }
+    Ok(best_height + 1)
+}
+
+// Reads blocks written by `export_chain` into the chain in the current data
+// directory, creating it if needed. Every block after genesis goes through
+// `Blockchain::add_block`, so it is validated like a block received from a
+// peer. Returns the chain and the number of blocks that were read.
+pub fn import_chain(path: &Path) -> Result<(Blockchain, usize), Box<dyn Error>> {
+    import_chain_into(path, Blockchain::init)
+}
+
+// Like `import_chain`, with `open_chain` opening or creating the chain.
+fn import_chain_into(
+    path: &Path,
+    open_chain: impl FnOnce() -> Blockchain,
+) -> Result<(Blockchain, usize), Box<dyn Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 6];
+    file.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err("not a chain export file".into());
+    }
+    if header[4] != FORMAT_VERSION {
+        return Err(format!("unsupported export format version {}", header[4]).into());
+    }
+
+    let mut reader: Box<dyn Read> = if header[5] & FLAG_GZIP != 0 {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let genesis = read_block(&mut reader)?.ok_or("export file holds no blocks")?;
+    let network = GLOBAL_CONFIG.get_network();
+    if genesis.get_hash() != network.get_genesis_hash() {
+        return Err(format!("export file is not for {}", network.get_name()).into());
+    }
+    let blockchain = open_chain();
+
+    let mut count = 1;
+    while let Some(block) = read_block(&mut reader)? {
+        blockchain.add_block(&block).map_err(|e| {
+            format!(
+                "block {} at height {}: {}",
+                block.get_hash(),
+                block.get_height(),
+                e
+            )
+        })?;
+        count += 1;
+        if count % 1000 == 0 {
+            info!("Imported {} blocks", count);
+        }
+    }
+    Ok((blockchain, count))
+}
+
+fn write_blocks(
+    blockchain: &Blockchain,
+    best_height: usize,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    for height in 0..=best_height {
+        let block = blockchain
+            .get_block_hash_by_height(height)
+            .and_then(|hash| blockchain.get_block(hash.as_bytes()))
+            .ok_or_else(|| format!("block at height {} is missing", height))?;
+        let bytes = block.serialize();
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+fn read_block(reader: &mut dyn Read) -> Result<Option<Block>, Box<dyn Error>> {
+    let mut len_bytes = [0u8; 4];
+    let mut filled = 0;
+    while filled < len_bytes.len() {
+        match reader.read(&mut len_bytes[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err("export file is truncated".into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(format!("block record of {} bytes is too large", len).into());
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::components::{
+        test_fixtures::{mine, spend_coinbase, test_wallet, TempFile},
+        transaction::Transaction,
+        utxoset::UTXOSet,
+        wallets::get_pub_key_hash,
+    };
+
+    fn import(path: &Path) -> Result<(Blockchain, usize), Box<dyn Error>> {
+        import_chain_into(path, Blockchain::temporary)
+    }
+
+    #[test]
+    fn import_restores_an_exported_chain() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (_, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        mine(&blockchain, &miner, std::slice::from_ref(&spend));
+
+        for compress in [false, true] {
+            let file = TempFile::new("mbcx");
+            let count = export_chain(&blockchain, &file.0, compress).unwrap();
+            assert_eq!(count, blockchain.get_best_height() + 1);
+
+            let (imported, read) = import(&file.0).unwrap();
+            assert_eq!(read, count);
+            assert_eq!(
+                imported.get_latest_blk_hash(),
+                blockchain.get_latest_blk_hash()
+            );
+            assert_eq!(imported.get_block_hashes(), blockchain.get_block_hashes());
+            let recipient_utxos =
+                UTXOSet::new(imported).find_utxo(&get_pub_key_hash(&recipient.get_address()));
+            assert_eq!(recipient_utxos.len(), 1);
+            assert_eq!(recipient_utxos[0].get_value(), 4);
+        }
+    }
+
+    #[test]
+    fn import_rejects_a_different_network() {
+        let miner = test_wallet(0);
+        let coinbase = Transaction::coinbase_tx(&miner.get_address(), 0, 0);
+        let record = Block::generate_genesis_block(&coinbase, 0, 0).serialize();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([FORMAT_VERSION, 0]);
+        bytes.extend((record.len() as u32).to_be_bytes());
+        bytes.extend(record);
+        let file = TempFile::new("mbcx");
+        fs::write(&file.0, bytes).unwrap();
+
+        let other = Blockchain::temporary();
+        let error = import_chain_into(&file.0, || other.clone()).err().unwrap();
+        assert!(error.to_string().contains("not for mainnet"));
+        assert_eq!(other.get_best_height(), 0);
+    }
+
+    #[test]
+    fn import_rejects_corrupt_files() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        mine(&blockchain, &miner, &[]);
+        let file = TempFile::new("mbcx");
+        export_chain(&blockchain, &file.0, false).unwrap();
+        let bytes = fs::read(&file.0).unwrap();
+
+        fs::write(&file.0, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(import(&file.0).is_err());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        fs::write(&file.0, wrong_magic).unwrap();
+        assert!(import(&file.0).is_err());
+
+        let mut wrong_version = bytes;
+        wrong_version[4] = FORMAT_VERSION + 1;
+        fs::write(&file.0, wrong_version).unwrap();
+        assert!(import(&file.0).is_err());
+    }
+}
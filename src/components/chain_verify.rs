@@ -0,0 +1,164 @@
+use data_encoding::HEXLOWER;
+
+use super::{
+    blockchain::{Blockchain, ChainError},
+    blocks::Block,
+    proof_of_work::ProofOfWork,
+    transaction::OutPoint,
+    utxoset::{UTXOEntry, UTXO_TREE},
+};
+
+// Level 0 checks linkage, heights and indexes. Each level adds its checks to
+// those of the levels below it.
+pub const LEVEL_POW: u8 = 1;
+pub const LEVEL_SIGNATURES: u8 = 2;
+pub const LEVEL_UTXO: u8 = 3;
+
+pub struct VerifyReport {
+    pub blocks_checked: usize,
+    // False when the UTXO set could not be recomputed because old block
+    // bodies have been pruned.
+    pub utxo_checked: bool,
+}
+
+// Walks back from the tip over at most `depth` stored blocks and returns the
+// first inconsistency as ChainError::Corrupted.
+pub fn verify_chain(
+    blockchain: &Blockchain,
+    depth: Option<usize>,
+    level: u8,
+) -> Result<VerifyReport, ChainError> {
+    if !blockchain.indexes_in_sync()? {
+        return Err(ChainError::Corrupted(String::from(
+            "height and transaction indexes diverge from the tip",
+        )));
+    }
+    if level >= LEVEL_UTXO && !blockchain.utxo_in_sync()? {
+        return Err(ChainError::Corrupted(String::from(
+            "UTXO set diverges from the tip",
+        )));
+    }
+
+    let mut blocks_checked = 0;
+    let mut iterator = blockchain.iterator();
+    let mut child: Option<Block> = None;
+    while depth.is_none_or(|depth| blocks_checked < depth) {
+        let Some(block) = iterator.next() else {
+            break;
+        };
+        if let Some(child) = &child {
+            if block.get_height() + 1 != child.get_height() {
+                return Err(corrupted(child, "height does not follow its parent"));
+            }
+        }
+        check_linkage(blockchain, &block)?;
+        if level >= LEVEL_POW && !ProofOfWork::new(block.clone()).validate() {
+            return Err(corrupted(&block, "invalid proof of work"));
+        }
+        if level >= LEVEL_SIGNATURES {
+            check_signatures(blockchain, &block)?;
+        }
+        blocks_checked += 1;
+        child = Some(block);
+    }
+
+    if let Some(child) = &child {
+        if child.get_height() != 0 && depth.is_none() && !blockchain.is_pruned() {
+            return Err(corrupted(child, "parent block is missing"));
+        }
+    }
+
+    let utxo_checked = level >= LEVEL_UTXO && !blockchain.is_pruned();
+    if utxo_checked {
+        check_utxo_set(blockchain)?;
+    }
+    Ok(VerifyReport {
+        blocks_checked,
+        utxo_checked,
+    })
+}
+
+fn check_linkage(blockchain: &Blockchain, block: &Block) -> Result<(), ChainError> {
+    let block_hash = block.get_hash();
+    if blockchain
+        .get_block_hash_by_height(block.get_height())
+        .as_deref()
+        != Some(block_hash)
+    {
+        return Err(corrupted(block, "height index points to another block"));
+    }
+    match blockchain.get_header(block_hash.as_bytes()) {
+        Some(header) if header.serialize() == block.get_header().serialize() => {}
+        _ => return Err(corrupted(block, "stored header does not match the block")),
+    }
+    if block.get_height() == 0 && block.get_prev_block_hash() != "None" {
+        return Err(corrupted(block, "genesis block has a parent"));
+    }
+
+    for (position, tx) in block.get_transactions().iter().enumerate() {
+        let location = blockchain.find_transaction_location(tx.get_id());
+        if location != Some((String::from(block_hash), position)) {
+            return Err(corrupted(
+                block,
+                &format!(
+                    "transaction index entry for {} is wrong",
+                    HEXLOWER.encode(tx.get_id())
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_signatures(blockchain: &Blockchain, block: &Block) -> Result<(), ChainError> {
+    let spent = blockchain.get_spent_outputs(block);
+    let prev_out = |outpoint: &OutPoint| -> Option<UTXOEntry> { spent.get(outpoint).cloned() };
+
+    for tx in block.get_transactions() {
+        if !tx.verify_with(prev_out) {
+            return Err(corrupted(
+                block,
+                &format!(
+                    "transaction {} has an invalid signature",
+                    HEXLOWER.encode(tx.get_id())
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_utxo_set(blockchain: &Blockchain) -> Result<(), ChainError> {
+    let mut expected = blockchain.find_utxo();
+    for item in blockchain.get_storage().iter(UTXO_TREE) {
+        let (key, value) = item?;
+        let outpoint = OutPoint::from_key(&key);
+        let matches = expected
+            .remove(&outpoint)
+            .is_some_and(|entry| bincode::serialize(&entry).unwrap() == value);
+        if !matches {
+            return Err(ChainError::Corrupted(format!(
+                "stored UTXO {}:{} does not match the chain",
+                HEXLOWER.encode(outpoint.get_txid()),
+                outpoint.get_vout()
+            )));
+        }
+    }
+    if let Some(outpoint) = expected.keys().next() {
+        return Err(ChainError::Corrupted(format!(
+            "UTXO {}:{} is missing from the stored set",
+            HEXLOWER.encode(outpoint.get_txid()),
+            outpoint.get_vout()
+        )));
+    }
+    Ok(())
+}
+
+fn corrupted(block: &Block, reason: &str) -> ChainError {
+    ChainError::Corrupted(format!(
+        "block {} at height {}: {}",
+        block.get_hash(),
+        block.get_height(),
+        reason
+    ))
+}
@@ -0,0 +1,449 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use data_encoding::HEXLOWER;
+
+use super::{
+    transaction::OutPoint,
+    utxoset::{UTXOEntry, UTXOSet},
+    wallet_crypto::random_bytes,
+};
+
+// Branch and bound gives up after this many steps and falls back to
+// largest-first.
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    // Looks for inputs that pay the amount and fee without any change,
+    // falling back to largest-first.
+    BranchAndBound,
+    LargestFirst,
+    SmallestFirst,
+    Random,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bnb" => Ok(Strategy::BranchAndBound),
+            "largest-first" => Ok(Strategy::LargestFirst),
+            "smallest-first" => Ok(Strategy::SmallestFirst),
+            "random" => Ok(Strategy::Random),
+            _ => Err(format!(
+                "unknown coin selection strategy {}, expected bnb, largest-first, smallest-first or random",
+                name
+            )),
+        }
+    }
+}
+
+// How a transaction picks its inputs. The fee is charged per input, so every
+// input has to be worth more than the fee to spend it.
+#[derive(Clone)]
+pub struct CoinSelection {
+    pub strategy: Strategy,
+    pub fee_per_input: i32,
+    // Outpoints that must be spent, before the strategy adds more if needed.
+    pub inputs: Vec<OutPoint>,
+}
+
+impl Default for CoinSelection {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::BranchAndBound,
+            fee_per_input: 0,
+            inputs: vec![],
+        }
+    }
+}
+
+pub struct Selection {
+    inputs: Vec<(OutPoint, UTXOEntry)>,
+    fee: i32,
+    change: i32,
+}
+
+impl Selection {
+    pub fn get_inputs(&self) -> &[(OutPoint, UTXOEntry)] {
+        self.inputs.as_slice()
+    }
+
+    pub fn get_fee(&self) -> i32 {
+        self.fee
+    }
+
+    // Zero when the inputs pay the amount and fee exactly, or when the
+    // change would cost more to spend than it is worth and goes to the fee.
+    pub fn get_change(&self) -> i32 {
+        self.change
+    }
+}
+
+#[derive(Debug)]
+pub enum SelectionError {
+    InsufficientFunds { available: i32, needed: i32 },
+    // A pinned outpoint that is spent, immature or not paying the sender.
+    UnspendableInput(String),
+    DuplicateInput(String),
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectionError::InsufficientFunds { available, needed } => write!(
+                f,
+                "not enough balance, {} is available after fees but {} is needed",
+                available, needed
+            ),
+            SelectionError::UnspendableInput(outpoint) => {
+                write!(f, "input {} can't be spent by the sender", outpoint)
+            }
+            SelectionError::DuplicateInput(outpoint) => {
+                write!(f, "input {} is given more than once", outpoint)
+            }
+        }
+    }
+}
+
+impl Error for SelectionError {}
+
+// Parses an outpoint written as `<txid>:<vout>`.
+pub fn parse_outpoint(s: &str) -> Option<OutPoint> {
+    let (txid, vout) = s.split_once(':')?;
+    let txid = HEXLOWER.decode(txid.to_lowercase().as_bytes()).ok()?;
+    Some(OutPoint::new(&txid, vout.parse().ok()?))
+}
+
+pub fn format_outpoint(outpoint: &OutPoint) -> String {
+    format!("{}:{}", HEXLOWER.encode(outpoint.get_txid()), outpoint.get_vout())
+}
+
+impl CoinSelection {
+    // Picks outputs locked to `pub_key_hash` that pay `amount` plus the fee
+    // and can be spent in the next block.
+    pub fn select_utxos(
+        &self,
+        utxo_set: &UTXOSet,
+        pub_key_hash: &[u8],
+        amount: i32,
+    ) -> Result<Selection, SelectionError> {
+        let spend_height = utxo_set.get_blockchain().get_best_height() + 1;
+        let mut pinned = vec![];
+        for outpoint in &self.inputs {
+            match utxo_set.get_entry(outpoint) {
+                Some(entry)
+                    if entry.is_locked_with_key(pub_key_hash) && entry.is_mature(spend_height) =>
+                {
+                    pinned.push((outpoint.clone(), entry))
+                }
+                _ => return Err(SelectionError::UnspendableInput(format_outpoint(outpoint))),
+            }
+        }
+        self.select(pinned, utxo_set.find_spendable_utxos(pub_key_hash), amount)
+    }
+
+    // `pinned` are the entries of `self.inputs`, `available` the other
+    // spendable outputs of the sender.
+    fn select(
+        &self,
+        pinned: Vec<(OutPoint, UTXOEntry)>,
+        available: Vec<(OutPoint, UTXOEntry)>,
+        amount: i32,
+    ) -> Result<Selection, SelectionError> {
+        for (idx, (outpoint, _)) in pinned.iter().enumerate() {
+            if pinned[..idx].iter().any(|(pin, _)| pin == outpoint) {
+                return Err(SelectionError::DuplicateInput(format_outpoint(outpoint)));
+            }
+        }
+        let pinned_value: i32 = pinned
+            .iter()
+            .map(|(_, entry)| self.effective_value(entry))
+            .sum();
+        let target = amount - pinned_value;
+
+        // Outputs worth less than the fee to spend them only lower the
+        // amount that can be sent.
+        let mut candidates: Vec<(OutPoint, UTXOEntry)> = available
+            .into_iter()
+            .filter(|(outpoint, entry)| {
+                self.effective_value(entry) > 0 && !pinned.iter().any(|(pin, _)| pin == outpoint)
+            })
+            .collect();
+
+        let chosen = if target <= 0 {
+            vec![]
+        } else {
+            let total: i32 = candidates.iter().map(|(_, e)| self.effective_value(e)).sum();
+            if total < target {
+                return Err(SelectionError::InsufficientFunds {
+                    available: pinned_value + total,
+                    needed: amount,
+                });
+            }
+            match self.strategy {
+                Strategy::BranchAndBound => {
+                    sort_by_value(&mut candidates, true);
+                    let values: Vec<i32> = candidates
+                        .iter()
+                        .map(|(_, e)| self.effective_value(e))
+                        .collect();
+                    match branch_and_bound(&values, target, self.cost_of_change()) {
+                        Some(indexes) => indexes.into_iter().map(|i| candidates[i].clone()).collect(),
+                        None => self.accumulate(candidates, target),
+                    }
+                }
+                Strategy::LargestFirst => {
+                    sort_by_value(&mut candidates, true);
+                    self.accumulate(candidates, target)
+                }
+                Strategy::SmallestFirst => {
+                    sort_by_value(&mut candidates, false);
+                    self.accumulate(candidates, target)
+                }
+                Strategy::Random => {
+                    shuffle(&mut candidates);
+                    self.accumulate(candidates, target)
+                }
+            }
+        };
+
+        let mut inputs = pinned;
+        inputs.extend(chosen);
+        let total: i32 = inputs.iter().map(|(_, entry)| entry.get_value()).sum();
+        let mut fee = self.fee_per_input * inputs.len() as i32;
+        let mut change = total - amount - fee;
+        if change < 0 {
+            // Only pinned inputs, and they don't cover the amount.
+            return Err(SelectionError::InsufficientFunds {
+                available: total - fee,
+                needed: amount,
+            });
+        }
+        if change <= self.cost_of_change() {
+            fee += change;
+            change = 0;
+        }
+        Ok(Selection {
+            inputs,
+            fee,
+            change,
+        })
+    }
+
+    fn effective_value(&self, entry: &UTXOEntry) -> i32 {
+        entry.get_value() - self.fee_per_input
+    }
+
+    // Spending a change output later costs one input fee, so change up to
+    // that is better given to the miner.
+    fn cost_of_change(&self) -> i32 {
+        self.fee_per_input
+    }
+
+    // Takes candidates in order until they cover the target.
+    fn accumulate(
+        &self,
+        candidates: Vec<(OutPoint, UTXOEntry)>,
+        target: i32,
+    ) -> Vec<(OutPoint, UTXOEntry)> {
+        let mut chosen = vec![];
+        let mut value = 0;
+        for (outpoint, entry) in candidates {
+            if value >= target {
+                break;
+            }
+            value += self.effective_value(&entry);
+            chosen.push((outpoint, entry));
+        }
+        chosen
+    }
+}
+
+fn sort_by_value(candidates: &mut [(OutPoint, UTXOEntry)], descending: bool) {
+    candidates.sort_by_key(|(_, entry)| entry.get_value());
+    if descending {
+        candidates.reverse();
+    }
+}
+
+// Depth first search over including or excluding each value, sorted
+// descending, for the selection in [target, target + cost_of_change] with
+// the least excess. Returns the indexes of the chosen values.
+fn branch_and_bound(values: &[i32], target: i32, cost_of_change: i32) -> Option<Vec<usize>> {
+    // Value still available from index i onwards.
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i];
+    }
+
+    let mut best: Option<(i32, Vec<usize>)> = None;
+    let mut selected: Vec<usize> = vec![];
+    let mut value = 0;
+    let mut index = 0;
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if value > target + cost_of_change || value + remaining[index] < target {
+            true
+        } else if value >= target {
+            let excess = value - target;
+            if best.as_ref().is_none_or(|(best_excess, _)| excess < *best_excess) {
+                best = Some((excess, selected.clone()));
+            }
+            // Adding more inputs only adds excess.
+            true
+        } else {
+            index == values.len()
+        };
+        if best.as_ref().is_some_and(|(excess, _)| *excess == 0) {
+            break;
+        }
+
+        if backtrack {
+            // Undo the last inclusion and try the branch without it.
+            let Some(last) = selected.pop() else {
+                break;
+            };
+            value -= values[last];
+            index = last + 1;
+            // Skipping equal values avoids searching the same sums twice.
+            while index < values.len() && values[index] == values[last] {
+                index += 1;
+            }
+        } else {
+            selected.push(index);
+            value += values[index];
+            index += 1;
+        }
+    }
+    best.map(|(_, indexes)| indexes)
+}
+
+// Fisher-Yates shuffle with the system random generator.
+fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let bytes = random_bytes(8);
+        let random = u64::from_be_bytes(bytes.try_into().unwrap());
+        items.swap(i, (random % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{test_fixtures::test_wallet, transaction::TxOutput};
+
+    fn utxo(id: u8, value: i32) -> (OutPoint, UTXOEntry) {
+        let output = TxOutput::new(value, &test_wallet(0).get_address());
+        let entry = UTXOEntry::new(&output, 1, false);
+        (OutPoint::new(&[id; 32], 0), entry)
+    }
+
+    fn coin_selection(strategy: Strategy, fee_per_input: i32) -> CoinSelection {
+        CoinSelection {
+            strategy,
+            fee_per_input,
+            inputs: vec![],
+        }
+    }
+
+    fn input_values(selection: &Selection) -> Vec<i32> {
+        selection
+            .get_inputs()
+            .iter()
+            .map(|(_, entry)| entry.get_value())
+            .collect()
+    }
+
+    #[test]
+    fn branch_and_bound_finds_an_exact_match() {
+        assert_eq!(branch_and_bound(&[10, 7, 5, 3], 8, 0), Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn branch_and_bound_prefers_the_least_excess() {
+        assert_eq!(branch_and_bound(&[9, 6, 5], 5, 2), Some(vec![2]));
+    }
+
+    #[test]
+    fn branch_and_bound_only_accepts_excess_up_to_the_cost_of_change() {
+        assert_eq!(branch_and_bound(&[6, 4], 5, 1), Some(vec![0]));
+        assert_eq!(branch_and_bound(&[6, 4], 5, 0), None);
+    }
+
+    #[test]
+    fn bnb_spends_an_exact_match_without_change() {
+        let available = vec![utxo(1, 10), utxo(2, 7), utxo(3, 5), utxo(4, 3)];
+        let selection = coin_selection(Strategy::BranchAndBound, 0)
+            .select(vec![], available, 8)
+            .unwrap();
+        assert_eq!(input_values(&selection), vec![5, 3]);
+        assert_eq!(selection.get_fee(), 0);
+        assert_eq!(selection.get_change(), 0);
+    }
+
+    #[test]
+    fn largest_first_returns_change() {
+        let available = vec![utxo(1, 2), utxo(2, 10), utxo(3, 5)];
+        let selection = coin_selection(Strategy::LargestFirst, 1)
+            .select(vec![], available, 6)
+            .unwrap();
+        assert_eq!(input_values(&selection), vec![10]);
+        assert_eq!(selection.get_fee(), 1);
+        assert_eq!(selection.get_change(), 3);
+    }
+
+    #[test]
+    fn change_worth_less_than_spending_it_goes_to_the_fee() {
+        let selection = coin_selection(Strategy::LargestFirst, 1)
+            .select(vec![], vec![utxo(1, 10)], 8)
+            .unwrap();
+        assert_eq!(selection.get_fee(), 2);
+        assert_eq!(selection.get_change(), 0);
+    }
+
+    #[test]
+    fn skips_outputs_worth_less_than_their_fee() {
+        let available = vec![utxo(1, 1), utxo(2, 2), utxo(3, 10)];
+        let selection = coin_selection(Strategy::SmallestFirst, 2)
+            .select(vec![], available, 5)
+            .unwrap();
+        assert_eq!(input_values(&selection), vec![10]);
+        assert_eq!(selection.get_change(), 3);
+    }
+
+    #[test]
+    fn pinned_inputs_are_spent_first() {
+        let selection = coin_selection(Strategy::LargestFirst, 0)
+            .select(vec![utxo(1, 4)], vec![utxo(2, 10)], 3)
+            .unwrap();
+        assert_eq!(input_values(&selection), vec![4]);
+        assert_eq!(selection.get_change(), 1);
+    }
+
+    #[test]
+    fn reports_insufficient_funds() {
+        let result = coin_selection(Strategy::BranchAndBound, 0).select(
+            vec![],
+            vec![utxo(1, 3), utxo(2, 4)],
+            10,
+        );
+        assert!(matches!(
+            result,
+            Err(SelectionError::InsufficientFunds {
+                available: 7,
+                needed: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_pinned_inputs() {
+        let result = coin_selection(Strategy::LargestFirst, 0).select(
+            vec![utxo(1, 4), utxo(1, 4)],
+            vec![],
+            6,
+        );
+        assert!(matches!(result, Err(SelectionError::DuplicateInput(_))));
+    }
+}
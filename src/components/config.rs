@@ -1,12 +1,19 @@
 use once_cell::sync::Lazy;
 use std::{collections::HashMap, env, sync::RwLock};
 
-pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
+use super::network::Network;
+
+pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2008";
+static DEFAULT_COINBASE_MATURITY: &str = "10";
 
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const COINBASE_MATURITY_KEY: &str = "COINBASE_MATURITY";
+const ADDRESS_INDEX_KEY: &str = "ADDRESS_INDEX";
+const PRUNE_KEY: &str = "PRUNE";
+const NETWORK_KEY: &str = "NETWORK";
 
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
@@ -19,8 +26,32 @@ impl Config {
             node_addr = addr;
         }
 
+        let mut coinbase_maturity = String::from(DEFAULT_COINBASE_MATURITY);
+        if let Ok(maturity) = env::var(COINBASE_MATURITY_KEY) {
+            if maturity.parse::<usize>().is_err() {
+                panic!("COINBASE_MATURITY must be a non-negative integer");
+            }
+            coinbase_maturity = maturity;
+        }
+
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        map.insert(String::from(COINBASE_MATURITY_KEY), coinbase_maturity);
+        if let Ok(address_index) = env::var(ADDRESS_INDEX_KEY) {
+            map.insert(String::from(ADDRESS_INDEX_KEY), address_index);
+        }
+        if let Ok(prune) = env::var(PRUNE_KEY) {
+            if !matches!(prune.parse::<usize>(), Ok(depth) if depth > 0) {
+                panic!("PRUNE must be a positive integer");
+            }
+            map.insert(String::from(PRUNE_KEY), prune);
+        }
+        if let Ok(network) = env::var(NETWORK_KEY) {
+            if Network::from_name(&network).is_none() {
+                panic!("NETWORK must be one of mainnet, testnet or regtest");
+            }
+            map.insert(String::from(NETWORK_KEY), network);
+        }
 
         Self {
             inner: RwLock::new(map),
@@ -45,6 +76,33 @@ impl Config {
         None
     }
 
+    pub fn get_coinbase_maturity(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner.get(COINBASE_MATURITY_KEY).unwrap().parse().unwrap()
+    }
+
+    pub fn is_address_index_enabled(&self) -> bool {
+        let inner = self.inner.read().unwrap();
+        matches!(
+            inner.get(ADDRESS_INDEX_KEY).map(String::as_str),
+            Some("1") | Some("true")
+        )
+    }
+
+    // Number of most recent block bodies to keep, or None when pruning is off.
+    pub fn get_prune_depth(&self) -> Option<usize> {
+        let inner = self.inner.read().unwrap();
+        inner.get(PRUNE_KEY).map(|depth| depth.parse().unwrap())
+    }
+
+    pub fn get_network(&self) -> Network {
+        let inner = self.inner.read().unwrap();
+        match inner.get(NETWORK_KEY) {
+            Some(name) => Network::from_name(name).unwrap(),
+            None => Network::Mainnet,
+        }
+    }
+
     pub fn is_miner(&self) -> bool {
         let inner = self.inner.read().unwrap();
         inner.contains_key(MINING_ADDRESS_KEY)
@@ -0,0 +1,200 @@
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use p256::{
+    elliptic_curve::{Field, PrimeField},
+    pkcs8::EncodePrivateKey,
+    FieldBytes, Scalar, SecretKey,
+};
+use sha2::Sha512;
+
+use super::wallet_crypto::random_bytes;
+
+// Derived addresses after the last used one that must all be unused before a
+// restore stops scanning.
+pub const GAP_LIMIT: u32 = 20;
+
+// 16 bytes of entropy give a 12 word phrase.
+const ENTROPY_LEN: usize = 16;
+
+const HARDENED: u32 = 0x8000_0000;
+
+// Keys live at m/44'/0'/0'/c'/i', where c is RECEIVE_CHAIN or CHANGE_CHAIN.
+// Every level is hardened, so derivation only needs private keys and follows
+// SLIP-0010 for the NIST P-256 curve.
+const ACCOUNT_PATH: [u32; 3] = [44 | HARDENED, HARDENED, HARDENED];
+
+pub const RECEIVE_CHAIN: u32 = 0;
+pub const CHANGE_CHAIN: u32 = 1;
+
+const MASTER_HMAC_KEY: &[u8] = b"Nist256p1 seed";
+
+type HmacSha512 = Hmac<Sha512>;
+
+pub fn generate_entropy() -> Vec<u8> {
+    random_bytes(ENTROPY_LEN)
+}
+
+// The mnemonic passphrase is always empty; the wallet passphrase protects the
+// stored entropy instead.
+pub fn entropy_to_seed(entropy: &[u8]) -> [u8; 64] {
+    to_mnemonic(entropy).to_seed("")
+}
+
+pub fn to_mnemonic(entropy: &[u8]) -> Mnemonic {
+    Mnemonic::from_entropy(entropy).expect("HD seed entropy has a valid length")
+}
+
+// Returns the PKCS#8 document of the key at `index` on `chain`.
+pub fn derive_pkcs8(seed: &[u8], chain: u32, index: u32) -> Vec<u8> {
+    let mut key = ExtendedKey::master(seed);
+    for child in ACCOUNT_PATH
+        .into_iter()
+        .chain([chain | HARDENED, index | HARDENED])
+    {
+        key = key.child(child);
+    }
+    let secret = SecretKey::from_bytes(&key.key.to_repr()).expect("derived key is not zero");
+    secret
+        .to_pkcs8_der()
+        .expect("unable to encode the derived key")
+        .as_bytes()
+        .to_vec()
+}
+
+struct ExtendedKey {
+    key: Scalar,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Self {
+        let mut data = seed.to_vec();
+        loop {
+            let digest = hmac_sha512(MASTER_HMAC_KEY, &data);
+            let (left, right) = digest.split_at(32);
+            if let Some(key) = parse_scalar(left).filter(|key| !bool::from(key.is_zero())) {
+                return Self {
+                    key,
+                    chain_code: right.try_into().unwrap(),
+                };
+            }
+            data = digest;
+        }
+    }
+
+    // Hardened child `index`. An out of range result is retried with the
+    // right half of the digest, as SLIP-0010 specifies.
+    fn child(&self, index: u32) -> Self {
+        let mut data = vec![0u8];
+        data.extend(self.key.to_repr());
+        data.extend(index.to_be_bytes());
+        loop {
+            let digest = hmac_sha512(&self.chain_code, &data);
+            let (left, right) = digest.split_at(32);
+            if let Some(tweak) = parse_scalar(left) {
+                let key = tweak + self.key;
+                if !bool::from(key.is_zero()) {
+                    return Self {
+                        key,
+                        chain_code: right.try_into().unwrap(),
+                    };
+                }
+            }
+            data = vec![1u8];
+            data.extend(right);
+            data.extend(index.to_be_bytes());
+        }
+    }
+}
+
+// None when the bytes are not below the curve order.
+fn parse_scalar(bytes: &[u8]) -> Option<Scalar> {
+    Scalar::from_repr(FieldBytes::clone_from_slice(bytes)).into()
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha512::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+    use p256::pkcs8::DecodePrivateKey;
+
+    // Test vector 1 of SLIP-0010 for nist256p1.
+    const VECTOR_1_SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+    fn hex(bytes: &[u8]) -> String {
+        HEXLOWER.encode(bytes)
+    }
+
+    fn assert_key(key: &ExtendedKey, private_key: &str, chain_code: &str) {
+        assert_eq!(hex(&key.key.to_repr()), private_key);
+        assert_eq!(hex(&key.chain_code), chain_code);
+    }
+
+    fn vector_1_master() -> ExtendedKey {
+        ExtendedKey::master(&HEXLOWER.decode(VECTOR_1_SEED.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn derives_the_slip10_master_key() {
+        assert_key(
+            &vector_1_master(),
+            "612091aaa12e22dd2abef664f8a01a82cae99ad7441b7ef8110424915c268bc2",
+            "beeb672fe4621673f722f38529c07392fecaa61015c80c34f29ce8b41b3cb6ea",
+        );
+    }
+
+    #[test]
+    fn derives_slip10_hardened_children() {
+        let master = vector_1_master();
+        assert_key(
+            &master.child(HARDENED),
+            "6939694369114c67917a182c59ddb8cafc3004e63ca5d3b84403ba8613debc0c",
+            "3460cea53e6a6bb5fb391eeef3237ffd8724bf0a40e94943c98b83825342ee11",
+        );
+        assert_key(
+            &master.child(28578 | HARDENED),
+            "06f0db126f023755d0b8d86d4591718a5210dd8d024e3e14b6159d63f53aa669",
+            "e94c8ebe30c2250a14713212f6449b20f3329105ea15b652ca5bdfc68f6c65c2",
+        );
+    }
+
+    // The first digest of this seed is not a valid key, so the master key
+    // comes from hashing it again.
+    #[test]
+    fn retries_an_invalid_slip10_master_key() {
+        let seed = "a7305bc8df8d0951f0cb224c0e95d7707cbdf2c6ce7e8d481fec69c7ff5e9446";
+        assert_key(
+            &ExtendedKey::master(&HEXLOWER.decode(seed.as_bytes()).unwrap()),
+            "3b8c18469a4634517d6d0b65448f8e6c62091b45540a1743c5846be55d47d88f",
+            "7762f9729fed06121fd13f326884c82f59aa95c57ac492ce8c9654e60efd130c",
+        );
+    }
+
+    // Expected keys computed with an independent SLIP-0010 implementation at
+    // m/44'/0'/0'/c'/i'.
+    #[test]
+    fn derive_pkcs8_follows_the_account_path() {
+        let seed = HEXLOWER.decode(VECTOR_1_SEED.as_bytes()).unwrap();
+        for (chain, index, expected) in [
+            (
+                RECEIVE_CHAIN,
+                0,
+                "b65f58d87f6ea99fcfe17f8ca5f68cb15811d5f0eb04a58f00855274b170fdab",
+            ),
+            (
+                CHANGE_CHAIN,
+                5,
+                "55c631a4804d78a41564429d27768b3b190146229ea1a03fbab0727c8c09c7f7",
+            ),
+        ] {
+            let secret = SecretKey::from_pkcs8_der(&derive_pkcs8(&seed, chain, index)).unwrap();
+            assert_eq!(hex(&secret.to_bytes()), expected);
+        }
+    }
+}
@@ -1,12 +1,8 @@
-use std::{
-    iter::repeat,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crypto::digest::Digest;
 use ring::{
     digest::{Context, SHA256},
-    rand::SystemRandom,
     signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING},
 };
 
@@ -24,16 +20,10 @@ pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
     digest.as_ref().to_vec()
 }
 
-pub fn create_key_pair() -> Vec<u8> {
-    let rng = SystemRandom::new();
-    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
-    pkcs8.as_ref().to_vec()
-}
-
 pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = crypto::ripemd160::Ripemd160::new();
     ripemd160.input(data);
-    let mut buf: Vec<u8> = repeat(0).take(ripemd160.output_bytes()).collect();
+    let mut buf: Vec<u8> = vec![0; ripemd160.output_bytes()];
     ripemd160.result(&mut buf);
     buf
 }
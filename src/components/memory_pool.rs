@@ -2,30 +2,52 @@ use std::{collections::HashMap, sync::RwLock};
 
 use data_encoding::HEXLOWER;
 
-use super::transaction::Transaction;
+use super::transaction::{OutPoint, Transaction};
+
+#[derive(Default)]
+struct Pool {
+    txs: HashMap<String, Transaction>,
+    // Outpoints spent by the pool transactions, with the id of the spender.
+    spent: HashMap<OutPoint, String>,
+}
 
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<Pool>,
 }
 
 impl MemoryPool {
     pub fn new() -> Self {
         Self {
-            inner: RwLock::new(HashMap::new()),
+            inner: RwLock::new(Pool::default()),
         }
     }
 
     pub fn contains(&self, txid_hex: &str) -> bool {
-        self.inner.read().unwrap().contains_key(txid_hex)
+        self.inner.read().unwrap().txs.contains_key(txid_hex)
     }
 
     pub fn add(&self, tx: Transaction) {
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        self.inner.write().unwrap().insert(txid_hex, tx);
+        let mut inner = self.inner.write().unwrap();
+        for vin in tx.get_vin() {
+            inner.spent.insert(vin.get_outpoint(), txid_hex.clone());
+        }
+        inner.txs.insert(txid_hex, tx);
+    }
+
+    // Id of another pool transaction spending an output `tx` spends.
+    pub fn find_conflict(&self, tx: &Transaction) -> Option<String> {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        let inner = self.inner.read().unwrap();
+        tx.get_vin()
+            .iter()
+            .filter_map(|vin| inner.spent.get(&vin.get_outpoint()))
+            .find(|spender| **spender != txid_hex)
+            .cloned()
     }
 
     pub fn get(&self, txid_hex: &str) -> Option<Transaction> {
-        if let Some(tx) = self.inner.read().unwrap().get(txid_hex) {
+        if let Some(tx) = self.inner.read().unwrap().txs.get(txid_hex) {
             return Some(tx.clone());
         }
         None
@@ -33,20 +55,28 @@ impl MemoryPool {
 
     pub fn remove(&self, txid_hex: &str) {
         let mut inner = self.inner.write().unwrap();
-        inner.remove(txid_hex);
+        let Some(tx) = inner.txs.remove(txid_hex) else {
+            return;
+        };
+        for vin in tx.get_vin() {
+            let outpoint = vin.get_outpoint();
+            if inner.spent.get(&outpoint).map(String::as_str) == Some(txid_hex) {
+                inner.spent.remove(&outpoint);
+            }
+        }
     }
 
     pub fn get_all(&self) -> Vec<Transaction> {
         let inner = self.inner.read().unwrap();
         let mut txs = vec![];
-        for (_, tx) in inner.iter() {
+        for (_, tx) in inner.txs.iter() {
             txs.push(tx.clone())
         }
         txs
     }
 
     pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+        self.inner.read().unwrap().txs.len()
     }
 }
 
@@ -83,12 +113,40 @@ impl BlockInTransit {
         }
     }
 
-    pub fn clear(&self) {
-        let mut inner = self.inner.write().unwrap();
-        inner.clear();
-    }
-
     pub fn len(&self) -> usize {
         self.inner.read().unwrap().len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        blockchain::Blockchain,
+        test_fixtures::{spend_coinbase, test_wallet},
+        transaction::TxOutput,
+    };
+
+    #[test]
+    fn finds_transactions_spending_the_same_output() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let double_spend = Transaction::spend(
+            &blockchain,
+            &miner,
+            &[coinbase_out],
+            vec![TxOutput::new(10, &recipient.get_address())],
+        );
+        let spend_hex = HEXLOWER.encode(spend.get_id());
+
+        let pool = MemoryPool::new();
+        assert_eq!(pool.find_conflict(&double_spend), None);
+        pool.add(spend.clone());
+        assert_eq!(pool.find_conflict(&double_spend), Some(spend_hex.clone()));
+        assert_eq!(pool.find_conflict(&spend), None);
+
+        pool.remove(&spend_hex);
+        assert_eq!(pool.find_conflict(&double_spend), None);
+    }
+}
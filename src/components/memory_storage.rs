@@ -0,0 +1,249 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, RwLock},
+};
+
+use super::storage::{KvIter, KvPair, Storage, StorageResult, StorageTransaction};
+
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: RwLock<HashMap<String, MemoryTree>>,
+    // Serializes writers so a transaction commit never overwrites a
+    // concurrent write to the same trees.
+    write_lock: Mutex<()>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        let trees = self.trees.read().unwrap();
+        Ok(trees.get(tree).and_then(|tree| tree.get(key)).cloned())
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut trees = self.trees.write().unwrap();
+        trees
+            .entry(String::from(tree))
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut trees = self.trees.write().unwrap();
+        Ok(trees.get_mut(tree).and_then(|tree| tree.remove(key)))
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter {
+        let trees = self.trees.read().unwrap();
+        let items: Vec<StorageResult<KvPair>> = trees
+            .get(tree)
+            .map(|tree| {
+                tree.range(prefix.to_vec()..)
+                    .take_while(|(k, _)| k.starts_with(prefix))
+                    .map(|(k, v)| Ok((k.clone(), v.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::new(items.into_iter())
+    }
+
+    fn clear(&self, tree: &str) -> StorageResult<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut trees = self.trees.write().unwrap();
+        trees.remove(tree);
+        Ok(())
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &dyn Fn(&dyn StorageTransaction) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let snapshot = {
+            let current = self.trees.read().unwrap();
+            trees
+                .iter()
+                .map(|name| {
+                    let tree = current.get(*name).cloned().unwrap_or_default();
+                    (String::from(*name), tree)
+                })
+                .collect()
+        };
+
+        let tx = MemoryTransaction {
+            trees: RefCell::new(snapshot),
+        };
+        f(&tx)?;
+
+        let mut current = self.trees.write().unwrap();
+        current.extend(tx.trees.into_inner());
+        Ok(())
+    }
+}
+
+struct MemoryTransaction {
+    trees: RefCell<HashMap<String, MemoryTree>>,
+}
+
+impl MemoryTransaction {
+    fn with_tree<T>(&self, name: &str, f: impl FnOnce(&mut MemoryTree) -> T) -> T {
+        let mut trees = self.trees.borrow_mut();
+        let tree = trees
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("tree {} is not part of the transaction", name));
+        f(tree)
+    }
+}
+
+impl StorageTransaction for MemoryTransaction {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.with_tree(tree, |tree| tree.get(key).cloned()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.with_tree(tree, |tree| tree.insert(key.to_vec(), value.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.with_tree(tree, |tree| tree.remove(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        blockchain::{Blockchain, ChainError},
+        blocks::Block,
+        storage::StorageError,
+        test_fixtures::{spend_coinbase, test_wallet},
+        transaction::{OutPoint, Transaction},
+        utxoset::{UTXOEntry, UTXOSet, UTXO_TREE},
+    };
+
+    fn get_entry(storage: &MemoryStorage, outpoint: &OutPoint) -> Option<UTXOEntry> {
+        let bytes = storage.get(UTXO_TREE, &outpoint.to_key()).unwrap()?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
+
+    fn connect(
+        storage: &MemoryStorage,
+        block: &Block,
+    ) -> Result<HashMap<OutPoint, UTXOEntry>, ChainError> {
+        let spent = RefCell::new(HashMap::new());
+        storage.transaction(&[UTXO_TREE], &|tx| {
+            *spent.borrow_mut() = UTXOSet::update(tx, block)?;
+            Ok(())
+        })?;
+        Ok(spent.into_inner())
+    }
+
+    fn disconnect(storage: &MemoryStorage, block: &Block, spent: &HashMap<OutPoint, UTXOEntry>) {
+        storage
+            .transaction(&[UTXO_TREE], &|tx| {
+                Ok(UTXOSet::disconnect(tx, block, spent)?)
+            })
+            .unwrap();
+    }
+
+    // The block holding the coinbase spent by `spend`, and a block after it
+    // that includes `spend`.
+    fn coinbase_and_spending_blocks() -> (Block, Block, OutPoint, Transaction) {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let block = blockchain.get_block_by_height(1).unwrap();
+        let coinbase = Transaction::coinbase_tx(&miner.get_address(), 2, 0);
+        let next_block = Block::new(
+            String::from(block.get_hash()),
+            &[coinbase, spend.clone()],
+            2,
+        );
+        (block, next_block, coinbase_out, spend)
+    }
+
+    #[test]
+    fn scan_prefix_returns_matching_keys_in_order() {
+        let storage = MemoryStorage::new();
+        for key in [b"ab2".as_slice(), b"b", b"ab1", b"a"] {
+            storage.insert("tree", key, &[]).unwrap();
+        }
+
+        let keys: Vec<Vec<u8>> = storage
+            .scan_prefix("tree", b"ab")
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"ab1".to_vec(), b"ab2".to_vec()]);
+        assert_eq!(storage.scan_prefix("missing", b"").count(), 0);
+    }
+
+    #[test]
+    fn aborted_transaction_discards_its_writes() {
+        let storage = MemoryStorage::new();
+        storage.insert("tree", b"kept", b"1").unwrap();
+
+        let result = storage.transaction(&["tree"], &|tx| {
+            tx.insert("tree", b"new", b"2")?;
+            tx.remove("tree", b"kept")?;
+            Err(ChainError::Corrupted(String::from("abort")).into())
+        });
+        assert!(matches!(result, Err(StorageError::Abort(_))));
+        assert!(matches!(
+            ChainError::from(result.unwrap_err()),
+            ChainError::Corrupted(_)
+        ));
+        assert_eq!(storage.get("tree", b"kept").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(storage.get("tree", b"new").unwrap(), None);
+    }
+
+    #[test]
+    fn connects_and_disconnects_utxos() {
+        let (block, next_block, coinbase_out, spend) = coinbase_and_spending_blocks();
+        let storage = MemoryStorage::new();
+        assert!(connect(&storage, &block).unwrap().is_empty());
+
+        let entry = get_entry(&storage, &coinbase_out).unwrap();
+        assert_eq!(entry.get_value(), 10);
+        assert_eq!(entry.get_height(), 1);
+        assert!(entry.is_coinbase());
+
+        let spent = connect(&storage, &next_block).unwrap();
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[&coinbase_out].get_value(), 10);
+        assert!(get_entry(&storage, &coinbase_out).is_none());
+        let paid = get_entry(&storage, &OutPoint::new(spend.get_id(), 0)).unwrap();
+        assert_eq!(paid.get_value(), 4);
+        assert!(!paid.is_coinbase());
+
+        disconnect(&storage, &next_block, &spent);
+        assert_eq!(get_entry(&storage, &coinbase_out).unwrap().get_value(), 10);
+        assert!(get_entry(&storage, &OutPoint::new(spend.get_id(), 0)).is_none());
+        assert!(get_entry(&storage, &OutPoint::new(spend.get_id(), 1)).is_none());
+        assert_eq!(storage.iter(UTXO_TREE).count(), 1);
+    }
+
+    #[test]
+    fn missing_input_leaves_the_utxo_set_unchanged() {
+        let (_, next_block, coinbase_out, _) = coinbase_and_spending_blocks();
+        let storage = MemoryStorage::new();
+
+        match connect(&storage, &next_block) {
+            Err(ChainError::MissingInput(outpoint)) => assert_eq!(outpoint, coinbase_out),
+            _ => panic!("expected a missing input"),
+        }
+        assert_eq!(storage.iter(UTXO_TREE).count(), 0);
+    }
+}
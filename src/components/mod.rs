@@ -1,11 +1,27 @@
+pub mod address_index;
 pub mod blockchain;
 pub mod blocks;
+pub mod chain_export;
+pub mod chain_verify;
+pub mod coin_selection;
 pub mod config;
+pub mod hd_keys;
 pub mod helpers;
 pub mod memory_pool;
+pub mod memory_storage;
+pub mod network;
 pub mod node;
+pub mod partial_transaction;
 pub mod proof_of_work;
+pub mod schema;
 pub mod server;
+pub mod storage;
+#[cfg(test)]
+pub mod test_fixtures;
 pub mod transaction;
+pub mod unlock_agent;
+pub mod utxo_snapshot;
 pub mod utxoset;
+pub mod wallet_crypto;
+pub mod wallet_history;
 pub mod wallets;
@@ -0,0 +1,119 @@
+use super::{blocks::Block, transaction::Transaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+struct NetworkParams {
+    name: &'static str,
+    data_dir: &'static str,
+    // Number of leading zero bits a block hash needs.
+    target_bits: i32,
+    genesis_timestamp: i64,
+    // Nobody holds the key for these addresses, so genesis rewards are
+    // unspendable.
+    genesis_address: &'static str,
+    genesis_hash: &'static str,
+}
+
+const MAINNET: NetworkParams = NetworkParams {
+    name: "mainnet",
+    data_dir: "data",
+    target_bits: 8,
+    genesis_timestamp: 1767225600000,
+    genesis_address: "1Fcos59Amf3K2DRSfoSLvKVonMMXtMVT1Z",
+    genesis_hash: "0063d794e3ad6ad4b9a005335a3fc0b8a1ded3b16c88d97a75b87ff06ae1fcc1",
+};
+
+const TESTNET: NetworkParams = NetworkParams {
+    name: "testnet",
+    data_dir: "data-testnet",
+    target_bits: 8,
+    genesis_timestamp: 1767225600001,
+    genesis_address: "1BtWYZFMbfToXpbqt7jpQUM36P7nxuC6UL",
+    genesis_hash: "006d18b81b397f8a7f93f683f7573dfd59a4aa8eb5f627486accbb1e8d2f134d",
+};
+
+const REGTEST: NetworkParams = NetworkParams {
+    name: "regtest",
+    data_dir: "data-regtest",
+    // Any hash meets the target, so blocks are mined instantly.
+    target_bits: 0,
+    genesis_timestamp: 1767225600002,
+    genesis_address: "1E47HyJwyvBAaqneKvsKNsHyjmznLj2d4o",
+    genesis_hash: "dd7c883e707458a5595517ac90955a29658234bf8d25a9dd49bfc8967b558bd7",
+};
+
+impl Network {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Network::Mainnet),
+            "testnet" => Some(Network::Testnet),
+            "regtest" => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+
+    fn params(&self) -> &'static NetworkParams {
+        match self {
+            Network::Mainnet => &MAINNET,
+            Network::Testnet => &TESTNET,
+            Network::Regtest => &REGTEST,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.params().name
+    }
+
+    // Directory under the working directory holding this network's chain.
+    pub fn get_data_dir(&self) -> &'static str {
+        self.params().data_dir
+    }
+
+    pub fn get_target_bits(&self) -> i32 {
+        self.params().target_bits
+    }
+
+    pub fn get_genesis_hash(&self) -> &'static str {
+        self.params().genesis_hash
+    }
+
+    // Rebuilds the genesis block from its fixed contents. Mining it is cheap
+    // and deterministic, and the result is checked against the hardcoded
+    // hash so an encoding change can't silently fork the network.
+    pub fn genesis_block(&self) -> Block {
+        let params = self.params();
+        let coinbase_tx = Transaction::coinbase_tx(params.genesis_address, 0, 0);
+        let block = Block::generate_genesis_block(
+            &coinbase_tx,
+            params.genesis_timestamp,
+            params.target_bits,
+        );
+        assert_eq!(
+            block.get_hash(),
+            params.genesis_hash,
+            "{} genesis block does not match its hardcoded hash",
+            params.name
+        );
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_blocks_match_their_hashes() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            let genesis = network.genesis_block();
+            assert_eq!(genesis.get_hash(), network.get_genesis_hash());
+            assert_eq!(Network::from_name(network.get_name()), Some(network));
+        }
+        assert_eq!(Network::from_name("devnet"), None);
+    }
+}
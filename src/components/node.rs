@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::RwLock};
+use std::sync::RwLock;
 
 #[derive(Clone)]
 pub struct Node {
@@ -13,10 +13,6 @@ impl Node {
     pub fn get_addr(&self) -> String {
         self.addr.clone()
     }
-
-    pub fn parse_socket_addr(&self) -> SocketAddr {
-        self.addr.parse().unwrap()
-    }
 }
 
 pub struct Nodes {
@@ -32,7 +28,7 @@ impl Nodes {
 
     pub fn add_node(&self, addr: String) {
         let mut inner = self.inner.write().unwrap();
-        if let None = inner.iter().position(|x| x.get_addr().eq(addr.as_str())) {
+        if !inner.iter().any(|x| x.get_addr().eq(addr.as_str())) {
             inner.push(Node::new(addr));
         }
     }
@@ -44,27 +40,12 @@ impl Nodes {
         }
     }
 
-    pub fn first(&self) -> Option<Node> {
-        let inner = self.inner.read().unwrap();
-        if let Some(node) = inner.first() {
-            return Some(node.clone());
-        }
-        None
-    }
-
     pub fn get_nodes(&self) -> Vec<Node> {
         self.inner.read().unwrap().to_vec()
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
-    }
-
     pub fn node_is_known(&self, addr: &str) -> bool {
         let inner = self.inner.read().unwrap();
-        if let Some(_) = inner.iter().position(|x| x.get_addr().eq(addr)) {
-            return true;
-        }
-        false
+        inner.iter().any(|x| x.get_addr().eq(addr))
     }
 }
@@ -0,0 +1,290 @@
+use std::{error::Error, fmt};
+
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    coin_selection::{CoinSelection, SelectionError},
+    transaction::{OutPoint, Transaction, TxOutput},
+    utxoset::{UTXOEntry, UTXOSet},
+    wallets::{convert_address, get_pub_key_hash, AddressKind, WalletError, Wallets},
+};
+
+const PARTIAL_TX_MAGIC: &[u8; 4] = b"MBPT";
+const PARTIAL_TX_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum PartialTransactionError {
+    InvalidEncoding,
+    UnsupportedVersion(u8),
+    AmountTooLarge,
+    Selection(SelectionError),
+    Wallet(WalletError),
+    Incomplete,
+}
+
+impl fmt::Display for PartialTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartialTransactionError::InvalidEncoding => {
+                write!(f, "not a hex encoded partially signed transaction")
+            }
+            PartialTransactionError::UnsupportedVersion(version) => {
+                write!(f, "unsupported partially signed transaction version {}", version)
+            }
+            PartialTransactionError::AmountTooLarge => write!(f, "the total amount is too large"),
+            PartialTransactionError::Selection(e) => write!(f, "{}", e),
+            PartialTransactionError::Wallet(e) => write!(f, "{}", e),
+            PartialTransactionError::Incomplete => {
+                write!(f, "the transaction is missing signatures")
+            }
+        }
+    }
+}
+
+impl Error for PartialTransactionError {}
+
+impl From<SelectionError> for PartialTransactionError {
+    fn from(e: SelectionError) -> Self {
+        PartialTransactionError::Selection(e)
+    }
+}
+
+impl From<WalletError> for PartialTransactionError {
+    fn from(e: WalletError) -> Self {
+        PartialTransactionError::Wallet(e)
+    }
+}
+
+// A transaction built on a node with the chain, carrying the outputs its
+// inputs spend so a wallet without the chain can sign it. Encoded as hex of
+// PARTIAL_TX_MAGIC, PARTIAL_TX_VERSION and the bincode of this struct.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartialTransaction {
+    tx: Transaction,
+    // The output spent by each input, in input order.
+    prev_outs: Vec<UTXOEntry>,
+}
+
+impl PartialTransaction {
+    // Pays `recipients` from the outputs of `from`, returning change to
+    // `change_address`. Needs no keys, so `from` may be a watch-only address.
+    pub fn create(
+        utxo_set: &UTXOSet,
+        from: &str,
+        recipients: &[(String, i32)],
+        change_address: &str,
+        coin_selection: &CoinSelection,
+    ) -> Result<Self, PartialTransactionError> {
+        let amount = recipients
+            .iter()
+            .try_fold(0i32, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or(PartialTransactionError::AmountTooLarge)?;
+        let selection = coin_selection.select_utxos(utxo_set, &get_pub_key_hash(from), amount)?;
+
+        let mut outputs: Vec<TxOutput> = recipients
+            .iter()
+            .map(|(to, amount)| TxOutput::new(*amount, to))
+            .collect();
+        if selection.get_change() > 0 {
+            outputs.push(TxOutput::new(selection.get_change(), change_address));
+        }
+
+        let inputs: Vec<OutPoint> = selection
+            .get_inputs()
+            .iter()
+            .map(|(outpoint, _)| outpoint.clone())
+            .collect();
+        Ok(Self {
+            tx: Transaction::unsigned(&inputs, outputs),
+            prev_outs: selection
+                .get_inputs()
+                .iter()
+                .map(|(_, entry)| entry.clone())
+                .collect(),
+        })
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, PartialTransactionError> {
+        let bytes = HEXLOWER
+            .decode(encoded.trim().to_lowercase().as_bytes())
+            .map_err(|_| PartialTransactionError::InvalidEncoding)?;
+        match bytes.strip_prefix(PARTIAL_TX_MAGIC) {
+            Some([PARTIAL_TX_VERSION, data @ ..]) => {
+                let partial: Self = bincode::deserialize(data)
+                    .map_err(|_| PartialTransactionError::InvalidEncoding)?;
+                if partial.prev_outs.len() != partial.tx.get_vin().len() {
+                    return Err(PartialTransactionError::InvalidEncoding);
+                }
+                Ok(partial)
+            }
+            Some([version, ..]) => Err(PartialTransactionError::UnsupportedVersion(*version)),
+            _ => Err(PartialTransactionError::InvalidEncoding),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = PARTIAL_TX_MAGIC.to_vec();
+        bytes.push(PARTIAL_TX_VERSION);
+        bytes.extend(bincode::serialize(self).expect("unable to serialize the transaction"));
+        HEXLOWER.encode(&bytes)
+    }
+
+    // Signs every unsigned input spending an address of `wallets` that holds
+    // its private key. Returns the number of inputs signed.
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize, PartialTransactionError> {
+        let mut signed = 0;
+        for idx in 0..self.prev_outs.len() {
+            if self.tx.get_vin()[idx].is_signed() {
+                continue;
+            }
+            let address = convert_address(self.prev_outs[idx].get_pub_key_hash());
+            let Some(wallet) = wallets.get_wallet(&address) else {
+                continue;
+            };
+            if wallet.get_kind() == AddressKind::WatchOnly {
+                continue;
+            }
+            let pkcs8 = wallets.get_private_key(&address)?;
+            let pub_key = wallet.get_pub_key().to_vec();
+            self.tx.sign_input(idx, &self.prev_outs[idx], &pub_key, &pkcs8);
+            signed += 1;
+        }
+        if self.is_complete() {
+            self.tx.update_id();
+        }
+        Ok(signed)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.tx.get_vin().iter().all(|vin| vin.is_signed())
+    }
+
+    // Checks the signatures against the carried previous outputs, without
+    // the chain.
+    pub fn verify(&self) -> bool {
+        self.tx.verify_with(|outpoint| self.get_prev_out(outpoint).cloned())
+    }
+
+    pub fn get_prev_out(&self, outpoint: &OutPoint) -> Option<&UTXOEntry> {
+        self.tx
+            .get_vin()
+            .iter()
+            .position(|vin| vin.get_outpoint() == *outpoint)
+            .map(|idx| &self.prev_outs[idx])
+    }
+
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    // The signed transaction, ready to broadcast.
+    pub fn into_transaction(self) -> Result<Transaction, PartialTransactionError> {
+        if !self.is_complete() {
+            return Err(PartialTransactionError::Incomplete);
+        }
+        Ok(self.tx)
+    }
+
+    // Computed from the carried previous outputs rather than
+    // Transaction::get_fee, which takes an unsigned single input transaction
+    // for a coinbase.
+    pub fn get_fee(&self) -> i32 {
+        let inputs: i32 = self.prev_outs.iter().map(|entry| entry.get_value()).sum();
+        let outputs: i32 = self.tx.get_vout().iter().map(|out| out.get_value()).sum();
+        inputs - outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{test_fixtures::test_wallet, wallets::Wallet};
+
+    // Spends an output of 10 paying each of `keys` and pays 7 to another
+    // address.
+    fn partial(keys: &[Wallet]) -> PartialTransaction {
+        let inputs: Vec<OutPoint> = (0..keys.len())
+            .map(|idx| OutPoint::new(&[idx as u8; 32], 0))
+            .collect();
+        let prev_outs = keys
+            .iter()
+            .map(|wallet| UTXOEntry::new(&TxOutput::new(10, &wallet.get_address()), 1, false))
+            .collect();
+        let recipient = test_wallet(9).get_address();
+        PartialTransaction {
+            tx: Transaction::unsigned(&inputs, vec![TxOutput::new(7, &recipient)]),
+            prev_outs,
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let partial = partial(&[test_wallet(0), test_wallet(1)]);
+        let decoded = PartialTransaction::decode(&partial.encode()).unwrap();
+        assert_eq!(decoded.encode(), partial.encode());
+        assert_eq!(decoded.get_fee(), 13);
+    }
+
+    #[test]
+    fn rejects_invalid_encodings() {
+        let encoded = partial(&[test_wallet(0)]).encode();
+        let mut bytes = HEXLOWER.decode(encoded.as_bytes()).unwrap();
+        bytes[PARTIAL_TX_MAGIC.len()] = PARTIAL_TX_VERSION + 1;
+        assert!(matches!(
+            PartialTransaction::decode(&HEXLOWER.encode(&bytes)),
+            Err(PartialTransactionError::UnsupportedVersion(_))
+        ));
+
+        let mut missing_prev_out = partial(&[test_wallet(0)]);
+        missing_prev_out.prev_outs.clear();
+        for encoded in [
+            String::from("not hex"),
+            HEXLOWER.encode(b"MBTX\x01"),
+            missing_prev_out.encode(),
+        ] {
+            assert!(matches!(
+                PartialTransaction::decode(&encoded),
+                Err(PartialTransactionError::InvalidEncoding)
+            ));
+        }
+    }
+
+    // A single unsigned input looks like a coinbase to Transaction::get_fee.
+    #[test]
+    fn fee_of_an_unsigned_single_input() {
+        assert_eq!(partial(&[test_wallet(0)]).get_fee(), 3);
+    }
+
+    #[test]
+    fn each_wallet_signs_its_own_inputs() {
+        let (first, second) = (test_wallet(0), test_wallet(1));
+        let mut partial = partial(&[first.clone(), second.clone()]);
+        assert_eq!(partial.sign(&Wallets::temporary(&[first])).unwrap(), 1);
+        assert!(!partial.is_complete());
+        assert!(matches!(
+            partial.clone().into_transaction(),
+            Err(PartialTransactionError::Incomplete)
+        ));
+
+        assert_eq!(partial.sign(&Wallets::temporary(&[second])).unwrap(), 1);
+        assert!(partial.is_complete());
+        assert!(partial.verify());
+        let tx = partial.into_transaction().unwrap();
+        assert!(!tx.get_id().is_empty());
+    }
+
+    #[test]
+    fn rejects_an_input_without_a_public_key() {
+        let wallet = test_wallet(0);
+        let mut partial = partial(std::slice::from_ref(&wallet));
+        let prev_out = partial.prev_outs[0].clone();
+        partial.tx.sign_input(0, &prev_out, &[], wallet.get_pkcs8());
+        assert!(partial.get_transaction().is_coinbase());
+        assert!(!partial.is_complete());
+        assert!(matches!(
+            partial.into_transaction(),
+            Err(PartialTransactionError::Incomplete)
+        ));
+    }
+}
@@ -2,23 +2,33 @@ use data_encoding::HEXLOWER;
 use num_bigint::{BigInt, Sign};
 use std::ops::ShlAssign;
 
-use crate::components::{blocks::Block, helpers::sha256_digest};
+use crate::components::{blocks::Block, config::GLOBAL_CONFIG, helpers::sha256_digest};
 
 const MAX_NONCE: i64 = i64::MAX;
-const TARGET_BITS: i32 = 8;
 
 pub struct ProofOfWork {
     block: Block,
+    target_bits: i32,
     target: BigInt,
 }
 
 impl ProofOfWork {
     pub fn new(block: Block) -> Self {
+        Self::with_target_bits(block, GLOBAL_CONFIG.get_network().get_target_bits())
+    }
+
+    // Proof of work at a given difficulty, for blocks of another network than
+    // the configured one.
+    pub fn with_target_bits(block: Block, target_bits: i32) -> Self {
         let mut target = BigInt::from(1);
 
-        target.shl_assign(256 - TARGET_BITS);
+        target.shl_assign(256 - target_bits);
 
-        Self { block, target }
+        Self {
+            block,
+            target_bits,
+            target,
+        }
     }
 
     pub fn prepare_data(&self, nonce: i64) -> Vec<u8> {
@@ -29,11 +39,18 @@ impl ProofOfWork {
         data_bytes.extend(pre_block_hash.as_bytes());
         data_bytes.extend(transaction_hash);
         data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(TARGET_BITS.to_be_bytes());
+        data_bytes.extend(self.target_bits.to_be_bytes());
         data_bytes.extend(nonce.to_be_bytes());
         data_bytes
     }
 
+    pub fn validate(&self) -> bool {
+        let data = self.prepare_data(self.block.get_nonce());
+        let hash = sha256_digest(data.as_slice());
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+        hash_int.lt(&self.target) && HEXLOWER.encode(hash.as_slice()).eq(self.block.get_hash())
+    }
+
     pub fn run(&self) -> (i64, String) {
         let mut nonce = 0;
         let mut hash = Vec::new();
@@ -59,3 +76,22 @@ impl ProofOfWork {
         (nonce, HEXLOWER.encode(hash.as_slice()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::transaction::Transaction;
+
+    #[test]
+    fn difficulty_depends_on_the_target_bits() {
+        let coinbase = Transaction::coinbase_tx("1Fcos59Amf3K2DRSfoSLvKVonMMXtMVT1Z", 0, 0);
+        let block = Block::generate_genesis_block(&coinbase, 0, 0);
+        assert_eq!(block.get_nonce(), 0);
+        assert!(ProofOfWork::with_target_bits(block.clone(), 0).validate());
+
+        let block = Block::generate_genesis_block(&coinbase, 0, 8);
+        assert!(block.get_hash().starts_with("00"));
+        assert!(ProofOfWork::with_target_bits(block.clone(), 8).validate());
+        assert!(!ProofOfWork::with_target_bits(block, 0).validate());
+    }
+}
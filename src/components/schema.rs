@@ -0,0 +1,218 @@
+use log::info;
+
+use super::{
+    address_index::ADDRESS_UTXO_TREE,
+    blockchain::{ChainError, TX_INDEX_TREE},
+    storage::{Storage, StorageResult, StorageTransaction},
+};
+
+pub const METADATA_TREE: &str = "metadata";
+pub const LATEST_BLOCK_HASH: &str = "latest_block_hash";
+pub const UTXO_BEST_BLOCK: &str = "utxo_best_block";
+pub const PRUNE_HEIGHT: &str = "prune_height";
+pub const ADDRESS_INDEX_BEST_BLOCK: &str = "address_index_best_block";
+pub const TX_INDEX_BEST_BLOCK: &str = "tx_index_best_block";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+// Version 0 databases have no metadata tree and keep the markers above in the
+// blocks tree.
+const LEGACY_MARKER_TREE: &str = "blocks";
+
+// Older databases keep the block an index is synced to in the index tree
+// itself, under this key.
+const LEGACY_INDEX_BEST_BLOCK: &str = "best_block";
+
+type Migration = fn(&dyn Storage) -> Result<(), ChainError>;
+
+// MIGRATIONS[n] upgrades a database from version n to n + 1 and records the
+// new version in the same transaction as its changes. A change to a stored
+// encoding, such as a new field on Block, gets a migration that rewrites the
+// affected entries.
+const MIGRATIONS: &[Migration] = &[
+    move_markers_to_metadata,
+    move_address_index_marker,
+    move_tx_index_marker,
+];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+// Brings the database up to SCHEMA_VERSION. An empty database is stamped with
+// the current version, and one written by a newer build is refused.
+pub fn migrate(storage: &dyn Storage) -> Result<(), ChainError> {
+    let mut version = match get_version(storage)? {
+        Some(version) => version,
+        None if storage.contains_key(LEGACY_MARKER_TREE, LATEST_BLOCK_HASH.as_bytes())? => 0,
+        None => {
+            storage.insert(
+                METADATA_TREE,
+                SCHEMA_VERSION_KEY.as_bytes(),
+                &SCHEMA_VERSION.to_be_bytes(),
+            )?;
+            return Ok(());
+        }
+    };
+    if version > SCHEMA_VERSION {
+        return Err(ChainError::UnsupportedSchema(version));
+    }
+
+    while version < SCHEMA_VERSION {
+        info!(
+            "Migrating database from schema version {} to {}",
+            version,
+            version + 1
+        );
+        MIGRATIONS[version as usize](storage)?;
+        version += 1;
+    }
+    Ok(())
+}
+
+fn get_version(storage: &dyn Storage) -> Result<Option<u32>, ChainError> {
+    let version = storage.get(METADATA_TREE, SCHEMA_VERSION_KEY.as_bytes())?;
+    Ok(version.map(|bytes| u32::from_be_bytes(bytes.as_slice().try_into().unwrap())))
+}
+
+fn set_version(tx: &dyn StorageTransaction, version: u32) -> StorageResult<()> {
+    tx.insert(
+        METADATA_TREE,
+        SCHEMA_VERSION_KEY.as_bytes(),
+        &version.to_be_bytes(),
+    )
+}
+
+fn move_markers_to_metadata(storage: &dyn Storage) -> Result<(), ChainError> {
+    storage.transaction(&[LEGACY_MARKER_TREE, METADATA_TREE], &|tx| {
+        for key in [LATEST_BLOCK_HASH, UTXO_BEST_BLOCK, PRUNE_HEIGHT] {
+            if let Some(value) = tx.remove(LEGACY_MARKER_TREE, key.as_bytes())? {
+                tx.insert(METADATA_TREE, key.as_bytes(), &value)?;
+            }
+        }
+        set_version(tx, 1)
+    })?;
+    Ok(())
+}
+
+fn move_address_index_marker(storage: &dyn Storage) -> Result<(), ChainError> {
+    move_index_marker(storage, ADDRESS_UTXO_TREE, ADDRESS_INDEX_BEST_BLOCK, 2)
+}
+
+fn move_tx_index_marker(storage: &dyn Storage) -> Result<(), ChainError> {
+    move_index_marker(storage, TX_INDEX_TREE, TX_INDEX_BEST_BLOCK, 3)
+}
+
+fn move_index_marker(
+    storage: &dyn Storage,
+    index_tree: &str,
+    key: &str,
+    version: u32,
+) -> Result<(), ChainError> {
+    storage.transaction(&[index_tree, METADATA_TREE], &|tx| {
+        if let Some(value) = tx.remove(index_tree, LEGACY_INDEX_BEST_BLOCK.as_bytes())? {
+            tx.insert(METADATA_TREE, key.as_bytes(), &value)?;
+        }
+        set_version(tx, version)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::memory_storage::MemoryStorage;
+
+    fn set_stored_version(storage: &dyn Storage, version: u32) {
+        storage
+            .insert(
+                METADATA_TREE,
+                SCHEMA_VERSION_KEY.as_bytes(),
+                &version.to_be_bytes(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_version_0_markers_to_the_metadata_tree() {
+        let storage = MemoryStorage::new();
+        for (key, value) in [
+            (LATEST_BLOCK_HASH, "tip"),
+            (UTXO_BEST_BLOCK, "utxo"),
+            (PRUNE_HEIGHT, "prune"),
+        ] {
+            storage
+                .insert(LEGACY_MARKER_TREE, key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        let legacy_key = LEGACY_INDEX_BEST_BLOCK.as_bytes();
+        storage
+            .insert(ADDRESS_UTXO_TREE, legacy_key, b"address")
+            .unwrap();
+        storage
+            .insert(TX_INDEX_TREE, legacy_key, b"txindex")
+            .unwrap();
+
+        migrate(&storage).unwrap();
+        assert_eq!(get_version(&storage).unwrap(), Some(SCHEMA_VERSION));
+        for (key, value) in [
+            (LATEST_BLOCK_HASH, "tip"),
+            (UTXO_BEST_BLOCK, "utxo"),
+            (PRUNE_HEIGHT, "prune"),
+            (ADDRESS_INDEX_BEST_BLOCK, "address"),
+            (TX_INDEX_BEST_BLOCK, "txindex"),
+        ] {
+            let stored = storage.get(METADATA_TREE, key.as_bytes()).unwrap();
+            assert_eq!(stored.as_deref(), Some(value.as_bytes()));
+        }
+        assert!(!storage
+            .contains_key(LEGACY_MARKER_TREE, LATEST_BLOCK_HASH.as_bytes())
+            .unwrap());
+        assert!(!storage.contains_key(ADDRESS_UTXO_TREE, legacy_key).unwrap());
+        assert!(!storage.contains_key(TX_INDEX_TREE, legacy_key).unwrap());
+    }
+
+    #[test]
+    fn runs_only_the_missing_migrations() {
+        let storage = MemoryStorage::new();
+        set_stored_version(&storage, 2);
+        storage
+            .insert(
+                TX_INDEX_TREE,
+                LEGACY_INDEX_BEST_BLOCK.as_bytes(),
+                b"txindex",
+            )
+            .unwrap();
+        // Version 2 already owns this key, so it must be left alone.
+        storage
+            .insert(
+                ADDRESS_UTXO_TREE,
+                LEGACY_INDEX_BEST_BLOCK.as_bytes(),
+                b"entry",
+            )
+            .unwrap();
+
+        migrate(&storage).unwrap();
+        assert_eq!(get_version(&storage).unwrap(), Some(SCHEMA_VERSION));
+        let stored = storage
+            .get(METADATA_TREE, TX_INDEX_BEST_BLOCK.as_bytes())
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some(&b"txindex"[..]));
+        assert!(storage
+            .contains_key(ADDRESS_UTXO_TREE, LEGACY_INDEX_BEST_BLOCK.as_bytes())
+            .unwrap());
+        assert!(!storage
+            .contains_key(METADATA_TREE, ADDRESS_INDEX_BEST_BLOCK.as_bytes())
+            .unwrap());
+    }
+
+    #[test]
+    fn stamps_new_databases_and_refuses_newer_ones() {
+        let storage = MemoryStorage::new();
+        migrate(&storage).unwrap();
+        assert_eq!(get_version(&storage).unwrap(), Some(SCHEMA_VERSION));
+
+        set_stored_version(&storage, SCHEMA_VERSION + 1);
+        assert!(matches!(
+            migrate(&storage),
+            Err(ChainError::UnsupportedSchema(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::components::{config::GLOBAL_CONFIG, utxoset::UTXOSet};
 
 use super::{
-    blockchain::{self, Blockchain},
+    blockchain::{check_transaction, Blockchain},
     blocks::Block,
     memory_pool::{BlockInTransit, MemoryPool},
     node::Nodes,
@@ -12,9 +12,10 @@ use log::{error, info};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
+use std::collections::HashSet;
 use std::error::Error;
 use std::{
-    io::{BufReader, Write},
+    io::{self, BufReader, Write},
     net::{Shutdown, SocketAddr, TcpListener, TcpStream},
     thread,
     time::Duration,
@@ -32,9 +33,9 @@ static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
     nodes
 });
 
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
 
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
 
 const TCP_WRITE_TIMEOUT: u64 = 1000;
 
@@ -50,16 +51,21 @@ impl Server {
     pub fn start(&self, addr: &str) {
         let listener = TcpListener::bind(addr).unwrap();
 
-        if addr.eq(CENERAL_NODE) == false {
-            let best_height = self.blockchain.get_best_height();
-            send_version(CENERAL_NODE, best_height);
+        if !addr.eq(CENERAL_NODE) {
+            send_version(CENERAL_NODE, &self.blockchain);
         }
 
         for stream in listener.incoming() {
             let blockchain = self.blockchain.clone();
             thread::spawn(|| match stream {
-                Ok(stream) => {}
-                Err(e) => {}
+                Ok(stream) => {
+                    if let Err(e) = serve(blockchain, stream) {
+                        error!("Error on serving client: {}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                }
             });
         }
     }
@@ -98,6 +104,12 @@ pub enum Payload {
         addr_from: String,
         version: usize,
         best_height: usize,
+        // Lowest height the peer still has a block body for.
+        #[serde(default)]
+        prune_height: usize,
+        // Identifies the peer's network; peers on another genesis are ignored.
+        #[serde(default)]
+        genesis_hash: String,
     },
 }
 
@@ -122,7 +134,7 @@ fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
         socket_addr,
         Payload::Inv {
             addr_from: node_addr,
-            op_type: op_type,
+            op_type,
             items: blocks.to_vec(),
         },
     );
@@ -141,20 +153,20 @@ fn send_block(addr: &str, block: &Block) {
     );
 }
 
-pub(crate) fn send_tx(addr: &str, tx: &Transaction) {
+pub(crate) fn send_tx(addr: &str, tx: &Transaction) -> io::Result<()> {
     let socket_addr = addr.parse().unwrap();
     let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
 
-    send_data(
+    try_send_data(
         socket_addr,
         Payload::Tx {
             addr_from: node_addr,
             transaction: tx.serialize(),
         },
-    );
+    )
 }
 
-fn send_version(addr: &str, height: usize) {
+fn send_version(addr: &str, blockchain: &Blockchain) {
     let socket_addr = addr.parse().unwrap();
     let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
 
@@ -163,7 +175,9 @@ fn send_version(addr: &str, height: usize) {
         Payload::Version {
             addr_from: node_addr,
             version: NODE_VERSION,
-            best_height: height,
+            best_height: blockchain.get_best_height(),
+            prune_height: blockchain.get_prune_height(),
+            genesis_hash: GLOBAL_CONFIG.get_network().get_genesis_hash().to_string(),
         },
     );
 }
@@ -181,20 +195,87 @@ fn send_get_blocks(addr: &str) {
 }
 
 fn send_data(addr: SocketAddr, payload: Payload) {
+    let _ = try_send_data(addr, payload);
+}
+
+fn try_send_data(addr: SocketAddr, payload: Payload) -> io::Result<()> {
     info!("Send payload: {:?}", payload);
 
-    let stream = TcpStream::connect(addr);
-    if stream.is_err() {
+    let mut stream = TcpStream::connect(addr).inspect_err(|_| {
         error!("The {} is not valid node address.", addr);
 
         GLOBAL_NODES.evict_node(addr.to_string().as_str());
-        return;
+    })?;
+    let _ = stream.set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)));
+    serde_json::to_writer(&stream, &payload)?;
+    stream.flush()
+}
+
+// Mines the memory pool into a block. When that fails, only the
+// transactions that no longer pass against the tip, for example because a
+// newer block spent their inputs, are dropped before mining the rest.
+fn mine_memory_pool(blockchain: &Blockchain, mining_address: &str) -> Option<Block> {
+    let mut retried = false;
+    loop {
+        let mut txs = GLOBAL_MEMORY_POOL.get_all();
+        if txs.is_empty() {
+            return None;
+        }
+        // Transactions whose inputs a newer block spent have no fee,
+        // mine_block rejects them below.
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let fees = txs
+            .iter()
+            .filter_map(|tx| tx.get_fee(|outpoint| utxo_set.get_entry(outpoint)))
+            .sum();
+        txs.push(Transaction::coinbase_tx(
+            mining_address,
+            blockchain.get_best_height() + 1,
+            fees,
+        ));
+
+        match blockchain.mine_block(&txs) {
+            Ok(block) => {
+                for tx in &txs {
+                    GLOBAL_MEMORY_POOL.remove(HEXLOWER.encode(tx.get_id()).as_str());
+                }
+                return Some(block);
+            }
+            Err(e) => {
+                error!("Unable to mine a block from the memory pool: {}", e);
+                if retried {
+                    return None;
+                }
+                drop_failing_transactions(blockchain);
+                retried = true;
+            }
+        }
     }
+}
 
-    let mut stream = stream.unwrap();
-    let _ = stream.set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)));
-    let _ = serde_json::to_writer(&stream, &payload);
-    let _ = stream.flush();
+fn drop_failing_transactions(blockchain: &Blockchain) {
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    let spend_height = blockchain.get_best_height() + 1;
+    let mut spent = HashSet::new();
+    for tx in GLOBAL_MEMORY_POOL.get_all() {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        let checked = check_transaction(&tx, |outpoint| utxo_set.get_entry(outpoint), spend_height);
+        let conflicts = tx
+            .get_vin()
+            .iter()
+            .any(|vin| spent.contains(&vin.get_outpoint()));
+        let reason = match checked {
+            Err(e) => e.to_string(),
+            // The first transaction spending an output keeps it.
+            Ok(_) if conflicts => String::from("spends the same output as another transaction"),
+            Ok(_) => {
+                spent.extend(tx.get_vin().iter().map(|vin| vin.get_outpoint()));
+                continue;
+            }
+        };
+        error!("Dropped tx {} from the memory pool: {}", txid_hex, reason);
+        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
+    }
 }
 
 fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
@@ -209,17 +290,18 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
         match payload {
             Payload::Block { addr_from, block } => {
                 let block = Block::deserialize(block.as_slice());
-                blockchain.add_block(&block);
-                info!("Added block {}", block.get_hash());
+                // A bad block must not stall the download of the blocks
+                // after it.
+                match blockchain.add_block(&block) {
+                    Ok(()) => info!("Added block {}", block.get_hash()),
+                    Err(e) => error!("Rejected block {}: {}", block.get_hash(), e),
+                }
 
                 if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
                     let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
 
                     send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
                 }
             }
             Payload::GetBlocks { addr_from } => {
@@ -239,7 +321,7 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 OpType::Tx => {
                     let txid_hex = HEXLOWER.encode(id.as_slice());
                     if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str()) {
-                        send_tx(addr_from.as_str(), &tx);
+                        let _ = send_tx(addr_from.as_str(), &tx);
                     }
                 }
             },
@@ -249,6 +331,26 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
             } => {
                 let tx = Transaction::deserialize(transaction.as_slice());
                 let txid = tx.get_id_bytes();
+
+                let utxo_set = UTXOSet::new(blockchain.clone());
+                if let Err(e) = check_transaction(
+                    &tx,
+                    |outpoint| utxo_set.get_entry(outpoint),
+                    blockchain.get_best_height() + 1,
+                ) {
+                    error!("Rejected tx {}: {}", HEXLOWER.encode(txid.as_slice()), e);
+                    continue;
+                }
+                // Only the first transaction spending an output gets in, so
+                // the pool never holds two that a block can't both include.
+                if let Some(spender) = GLOBAL_MEMORY_POOL.find_conflict(&tx) {
+                    error!(
+                        "Rejected tx {}: spends the same output as tx {} in the memory pool",
+                        HEXLOWER.encode(txid.as_slice()),
+                        spender
+                    );
+                    continue;
+                }
                 GLOBAL_MEMORY_POOL.add(tx);
 
                 let node_addr = GLOBAL_CONFIG.get_node_addr();
@@ -264,27 +366,18 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                             continue;
                         }
 
-                        send_inv(addr_from.as_str(), OpType::Tx, &vec![txid.clone()]);
+                        send_inv(addr_from.as_str(), OpType::Tx, std::slice::from_ref(&txid));
                     }
                 }
 
                 if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
                     let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::coinbase_tx(mining_address.as_str());
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
-                    txs.push(coinbase_tx);
-
-                    let new_block = blockchain.mine_block(&txs);
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+                    let Some(new_block) = mine_memory_pool(&blockchain, &mining_address) else {
+                        continue;
+                    };
 
                     info!("New block {} is mined!!!", new_block.get_hash());
 
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
-
                     let nodes = GLOBAL_NODES.get_nodes();
 
                     for node in &nodes {
@@ -306,18 +399,20 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 items,
             } => match op_type {
                 OpType::Block => {
+                    let Some(block_hash) = items.first() else {
+                        continue;
+                    };
                     GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
 
-                    let block_hash = items.get(0).unwrap();
                     send_get_data(addr_from.as_str(), OpType::Block, block_hash);
 
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
                 }
                 OpType::Tx => {
-                    let txid = items.get(0).unwrap();
+                    let txid = items.first().unwrap();
                     let txid_hex = HEXLOWER.encode(txid);
 
-                    if GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) == false {
+                    if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) {
                         send_get_data(addr_from.as_str(), OpType::Tx, txid);
                     }
                 }
@@ -326,19 +421,43 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 addr_from,
                 version,
                 best_height,
+                prune_height,
+                genesis_hash,
             } => {
-                info!("Version {}, best_height {}", version, best_height);
+                info!(
+                    "Version {}, best_height {}, prune_height {}",
+                    version, best_height, prune_height
+                );
+
+                let network = GLOBAL_CONFIG.get_network();
+                if genesis_hash != network.get_genesis_hash() {
+                    error!(
+                        "Peer {} is not on {} (genesis {}), ignoring it",
+                        addr_from,
+                        network.get_name(),
+                        genesis_hash
+                    );
+                    GLOBAL_NODES.evict_node(addr_from.as_str());
+                    continue;
+                }
 
                 let local_best_height = blockchain.get_best_height();
                 if local_best_height < best_height {
-                    send_get_blocks(addr_from.as_str());
+                    if prune_height > local_best_height + 1 {
+                        info!(
+                            "Peer {} has pruned blocks this node still needs, not syncing from it",
+                            addr_from
+                        );
+                    } else {
+                        send_get_blocks(addr_from.as_str());
+                    }
                 }
 
                 if local_best_height > best_height {
-                    send_version(addr_from.as_str(), blockchain.get_best_height());
+                    send_version(addr_from.as_str(), &blockchain);
                 }
 
-                if GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) == false {
+                if !GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) {
                     GLOBAL_NODES.add_node(addr_from);
                 }
             }
@@ -349,3 +468,79 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        test_fixtures::{spend_coinbase, test_wallet},
+        transaction::{OutPoint, TxOutput},
+    };
+
+    // Serves `payload` as if a peer had sent it.
+    fn receive(blockchain: &Blockchain, payload: &Payload) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        serde_json::to_writer(&peer, payload).unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        serve(blockchain.clone(), stream).unwrap();
+    }
+
+    fn version(addr_from: &str, genesis_hash: &str) -> Payload {
+        Payload::Version {
+            addr_from: String::from(addr_from),
+            version: NODE_VERSION,
+            best_height: 0,
+            prune_height: 0,
+            genesis_hash: String::from(genesis_hash),
+        }
+    }
+
+    #[test]
+    fn ignores_peers_on_another_network() {
+        let blockchain = Blockchain::temporary();
+        let foreign = "127.0.0.1:2101";
+        GLOBAL_NODES.add_node(String::from(foreign));
+        receive(&blockchain, &version(foreign, &"0".repeat(64)));
+        assert!(!GLOBAL_NODES.node_is_known(foreign));
+
+        let peer = "127.0.0.1:2102";
+        let genesis_hash = GLOBAL_CONFIG.get_network().get_genesis_hash();
+        receive(&blockchain, &version(peer, genesis_hash));
+        assert!(GLOBAL_NODES.node_is_known(peer));
+    }
+
+    #[test]
+    fn mining_drops_only_failing_transactions() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let inputs = std::slice::from_ref(&coinbase_out);
+        let conflicting = Transaction::spend(
+            &blockchain,
+            &miner,
+            inputs,
+            vec![TxOutput::new(10, &recipient.get_address())],
+        );
+        let overpaying = Transaction::spend(
+            &blockchain,
+            &miner,
+            inputs,
+            vec![TxOutput::new(11, &recipient.get_address())],
+        );
+        for tx in [spend, conflicting, overpaying] {
+            GLOBAL_MEMORY_POOL.add(tx);
+        }
+
+        let block = mine_memory_pool(&blockchain, &miner.get_address()).unwrap();
+        assert_eq!(block.get_transactions().len(), 2);
+        assert_eq!(GLOBAL_MEMORY_POOL.len(), 0);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert!(utxo_set.get_entry(&coinbase_out).is_none());
+        let mined = &block.get_transactions()[0];
+        assert!(utxo_set
+            .get_entry(&OutPoint::new(mined.get_id(), 0))
+            .is_some());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{error::Error, fmt, path::Path};
+
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Db, Tree,
+};
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+pub type KvIter = Box<dyn Iterator<Item = StorageResult<KvPair>>>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sled(sled::Error),
+    // A concurrent transaction touched the same keys. Returned from inside a
+    // transaction closure it makes the backend retry the closure.
+    Conflict,
+    // The error a transaction closure gave up with. It is returned from
+    // `Storage::transaction` unchanged.
+    Abort(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Sled(e) => write!(f, "{}", e),
+            StorageError::Conflict => write!(f, "transaction conflict"),
+            StorageError::Abort(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError::Sled(e)
+    }
+}
+
+impl From<TransactionError<StorageError>> for StorageError {
+    fn from(e: TransactionError<StorageError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StorageError::Sled(e),
+        }
+    }
+}
+
+impl From<UnabortableTransactionError> for StorageError {
+    fn from(e: UnabortableTransactionError) -> Self {
+        match e {
+            UnabortableTransactionError::Conflict => StorageError::Conflict,
+            UnabortableTransactionError::Storage(e) => StorageError::Sled(e),
+        }
+    }
+}
+
+// A key-value store made of named trees. Keys within a tree are ordered
+// bytewise, which `scan_prefix` relies on.
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()>;
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+
+    fn contains_key(&self, tree: &str, key: &[u8]) -> StorageResult<bool> {
+        Ok(self.get(tree, key)?.is_some())
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter;
+
+    fn iter(&self, tree: &str) -> KvIter {
+        self.scan_prefix(tree, &[])
+    }
+
+    fn clear(&self, tree: &str) -> StorageResult<()>;
+
+    // Runs `f` atomically over `trees`. The closure may run more than once
+    // and must not call the non-transactional methods above.
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &dyn Fn(&dyn StorageTransaction) -> StorageResult<()>,
+    ) -> StorageResult<()>;
+}
+
+pub trait StorageTransaction {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()>;
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+}
+
+pub struct SledStorage {
+    db: Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> StorageResult<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    fn tree(&self, name: &str) -> StorageResult<Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.tree(tree)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.remove(key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter {
+        match self.tree(tree) {
+            Ok(tree) => Box::new(tree.scan_prefix(prefix).map(|item| {
+                let (k, v) = item?;
+                Ok((k.to_vec(), v.to_vec()))
+            })),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn clear(&self, tree: &str) -> StorageResult<()> {
+        self.tree(tree)?.clear()?;
+        Ok(())
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &dyn Fn(&dyn StorageTransaction) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        let sled_trees = trees
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<StorageResult<Vec<Tree>>>()?;
+        sled_trees[..].transaction(|tx_trees| {
+            let tx = SledTransaction {
+                names: trees,
+                trees: tx_trees,
+            };
+            match f(&tx) {
+                Ok(()) => Ok(()),
+                Err(StorageError::Conflict) => Err(ConflictableTransactionError::Conflict),
+                Err(e) => Err(ConflictableTransactionError::Abort(e)),
+            }
+        })?;
+        Ok(())
+    }
+}
+
+struct SledTransaction<'a> {
+    names: &'a [&'a str],
+    trees: &'a [TransactionalTree],
+}
+
+impl SledTransaction<'_> {
+    fn tree(&self, name: &str) -> &TransactionalTree {
+        let idx = self
+            .names
+            .iter()
+            .position(|tree| *tree == name)
+            .unwrap_or_else(|| panic!("tree {} is not part of the transaction", name));
+        &self.trees[idx]
+    }
+}
+
+impl StorageTransaction for SledTransaction<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree).get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.tree(tree).insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree).remove(key)?.map(|value| value.to_vec()))
+    }
+}
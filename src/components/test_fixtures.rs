@@ -0,0 +1,69 @@
+use std::{env, fs, path::PathBuf};
+
+use uuid::Uuid;
+
+use super::{
+    blockchain::Blockchain,
+    blocks::Block,
+    config::GLOBAL_CONFIG,
+    hd_keys::{derive_pkcs8, RECEIVE_CHAIN},
+    transaction::{OutPoint, Transaction, TxOutput},
+    wallets::Wallet,
+};
+
+// A wallet with a fixed key, a different one for each `index`.
+pub fn test_wallet(index: u32) -> Wallet {
+    Wallet::from_pkcs8(derive_pkcs8(&[1; 64], RECEIVE_CHAIN, index))
+}
+
+// Mines `transactions` after a coinbase paying `miner`.
+pub fn mine(blockchain: &Blockchain, miner: &Wallet, transactions: &[Transaction]) -> Block {
+    let height = blockchain.get_best_height() + 1;
+    let mut txs = vec![Transaction::coinbase_tx(&miner.get_address(), height, 0)];
+    txs.extend_from_slice(transactions);
+    blockchain.mine_block(&txs).unwrap()
+}
+
+// Mines empty blocks until the coinbase of the tip can be spent.
+pub fn mature(blockchain: &Blockchain, miner: &Wallet) {
+    for _ in 0..GLOBAL_CONFIG.get_coinbase_maturity() {
+        mine(blockchain, miner, &[]);
+    }
+}
+
+// Mines a coinbase paying `miner` until it matures and returns a transaction
+// spending it with 4 to `recipient` and 6 back to `miner`.
+pub fn spend_coinbase(
+    blockchain: &Blockchain,
+    miner: &Wallet,
+    recipient: &Wallet,
+) -> (OutPoint, Transaction) {
+    let block = mine(blockchain, miner, &[]);
+    mature(blockchain, miner);
+    let coinbase_out = OutPoint::new(block.get_transactions()[0].get_id(), 0);
+    let spend = Transaction::spend(
+        blockchain,
+        miner,
+        std::slice::from_ref(&coinbase_out),
+        vec![
+            TxOutput::new(4, &recipient.get_address()),
+            TxOutput::new(6, &miner.get_address()),
+        ],
+    );
+    (coinbase_out, spend)
+}
+
+// A file in the temporary directory that is removed when dropped.
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(prefix: &str) -> Self {
+        Self(env::temp_dir().join(format!("{}-{}", prefix, Uuid::new_v4())))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
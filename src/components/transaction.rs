@@ -1,17 +1,55 @@
-use data_encoding::HEXLOWER;
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
 use super::{
     blockchain::Blockchain,
-    helpers::{
-        base58_decode, ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify, sha256_digest,
-    },
-    utxoset::UTXOSet,
+    coin_selection::CoinSelection,
+    helpers::{ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify, sha256_digest},
+    utxoset::{UTXOEntry, UTXOSet},
     wallets::{self, Wallets},
 };
 
-const INCENTIVE: i32 = 10;
+pub const INCENTIVE: i32 = 10;
+
+pub const OUTPOINT_KEY_LEN: usize = 36;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    txid: Vec<u8>,
+    vout: usize,
+}
+
+impl OutPoint {
+    pub fn new(txid: &[u8], vout: usize) -> Self {
+        Self {
+            txid: txid.to_vec(),
+            vout,
+        }
+    }
+
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_vout(&self) -> usize {
+        self.vout
+    }
+
+    pub fn to_key(&self) -> Vec<u8> {
+        let mut key = self.txid.clone();
+        key.extend((self.vout as u32).to_be_bytes());
+        key
+    }
+
+    pub fn from_key(key: &[u8]) -> Self {
+        let (txid, vout) = key.split_at(key.len() - 4);
+        Self {
+            txid: txid.to_vec(),
+            vout: u32::from_be_bytes(vout.try_into().unwrap()) as usize,
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct TxInput {
@@ -43,9 +81,19 @@ impl TxInput {
         self.pub_key.as_slice()
     }
 
+    pub fn get_outpoint(&self) -> OutPoint {
+        OutPoint::new(self.txid.as_slice(), self.vout)
+    }
+
+    // An input without a public key would make a single input transaction
+    // look like a coinbase, so it needs both.
+    pub fn is_signed(&self) -> bool {
+        !self.signature.is_empty() && !self.pub_key.is_empty()
+    }
+
     pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
         let locking_hash = wallets::hash_pub_key(self.pub_key.as_slice());
-        return locking_hash.eq(pub_key_hash);
+        locking_hash.eq(pub_key_hash)
     }
 }
 
@@ -75,9 +123,7 @@ impl TxOutput {
     }
 
     fn lock(&mut self, address: &str) {
-        let pyaload = base58_decode(address);
-        let pub_key_hash = pyaload[1..pyaload.len() - wallets::ADDRESS_CHECK_SUM_LEN].to_vec();
-        self.pub_key_hash = pub_key_hash;
+        self.pub_key_hash = wallets::get_pub_key_hash(address);
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -93,10 +139,15 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    pub fn coinbase_tx(to: &str) -> Self {
-        let txout = TxOutput::new(INCENTIVE, to);
-        let mut txinput = TxInput::default();
-        txinput.signature = Uuid::new_v4().as_bytes().to_vec();
+    // The block height in the input keeps coinbase ids unique without any
+    // randomness, so a genesis block can be rebuilt exactly. The miner is paid
+    // the block reward plus the fees of the block's transactions.
+    pub fn coinbase_tx(to: &str, height: usize, fees: i32) -> Self {
+        let txout = TxOutput::new(INCENTIVE + fees, to);
+        let txinput = TxInput {
+            signature: (height as u64).to_be_bytes().to_vec(),
+            ..Default::default()
+        };
 
         let mut tx = Self {
             id: vec![],
@@ -108,6 +159,20 @@ impl Transaction {
         tx
     }
 
+    // A transaction spending `inputs` that still has to be signed, see
+    // sign_input.
+    pub fn unsigned(inputs: &[OutPoint], outputs: Vec<TxOutput>) -> Self {
+        let vin = inputs
+            .iter()
+            .map(|outpoint| TxInput::new(outpoint.get_txid(), outpoint.get_vout()))
+            .collect();
+        Self {
+            id: vec![],
+            vin,
+            vout: outputs,
+        }
+    }
+
     pub fn get_id(&self) -> &[u8] {
         self.id.as_slice()
     }
@@ -132,35 +197,66 @@ impl Transaction {
         bincode::deserialize(bytes).unwrap()
     }
 
-    pub fn utxo_transaction(from: &str, to: &str, amount: i32, utxo_set: &UTXOSet) -> Transaction {
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).expect("unable to found wallet");
-        let pub_key_hash = wallets::hash_pub_key(wallet.get_pub_key());
+    pub fn utxo_transaction(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: i32,
+        utxo_set: &UTXOSet,
+        coin_selection: &CoinSelection,
+    ) -> Transaction {
+        let recipients = [(String::from(to), amount)];
+        Self::utxo_transaction_many(wallets, from, &recipients, utxo_set, coin_selection)
+    }
 
-        let (accumulated_amount, valid_outputs) =
-            utxo_set.find_spendable_outputs(pub_key_hash.as_slice(), amount);
+    // Pays every (address, amount) pair in one transaction, with a single
+    // change output to a new change address of the wallet. Wallets without an
+    // HD seed return the change to `from`.
+    pub fn utxo_transaction_many(
+        wallets: &mut Wallets,
+        from: &str,
+        recipients: &[(String, i32)],
+        utxo_set: &UTXOSet,
+        coin_selection: &CoinSelection,
+    ) -> Transaction {
+        let amount = recipients
+            .iter()
+            .try_fold(0i32, |total, (_, amount)| total.checked_add(*amount))
+            .expect("Error: The total amount is too large");
+        let wallet = wallets
+            .get_wallet(from)
+            .expect("unable to found wallet")
+            .clone();
+        let pkcs8 = wallets
+            .get_private_key(from)
+            .unwrap_or_else(|e| panic!("Error: {}", e));
+        let pub_key_hash = wallets::hash_pub_key(wallet.get_pub_key());
 
-        if accumulated_amount < amount {
-            panic!("Error: Not enough balance.");
-        }
+        let selection = coin_selection
+            .select_utxos(utxo_set, &pub_key_hash, amount)
+            .unwrap_or_else(|e| panic!("Error: {}", e));
 
         let mut inputs = vec![];
-        for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            for out in outs {
-                let input = TxInput {
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: vec![],
-                    pub_key: wallet.get_pub_key().to_vec(),
-                };
-
-                inputs.push(input);
-            }
+        for (outpoint, _) in selection.get_inputs() {
+            let input = TxInput {
+                txid: outpoint.get_txid().to_vec(),
+                vout: outpoint.get_vout(),
+                signature: vec![],
+                pub_key: wallet.get_pub_key().to_vec(),
+            };
+
+            inputs.push(input);
         }
-        let mut outputs = vec![TxOutput::new(amount, to)];
-        if accumulated_amount > amount {
-            outputs.push(TxOutput::new(accumulated_amount - amount, from));
+        let mut outputs: Vec<TxOutput> = recipients
+            .iter()
+            .map(|(to, amount)| TxOutput::new(*amount, to))
+            .collect();
+        if selection.get_change() > 0 {
+            let change_address = wallets
+                .create_change_address()
+                .unwrap_or_else(|e| panic!("Error: {}", e))
+                .unwrap_or_else(|| String::from(from));
+            outputs.push(TxOutput::new(selection.get_change(), &change_address));
         }
 
         let mut tx = Transaction {
@@ -171,10 +267,53 @@ impl Transaction {
 
         tx.id = tx.hash();
 
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
+        tx.sign(utxo_set.get_blockchain(), &pkcs8);
+        tx
+    }
+
+    // Spends `inputs`, all owned by `wallet`, without coin selection.
+    #[cfg(test)]
+    pub fn spend(
+        blockchain: &Blockchain,
+        wallet: &wallets::Wallet,
+        inputs: &[OutPoint],
+        outputs: Vec<TxOutput>,
+    ) -> Transaction {
+        let vin = inputs
+            .iter()
+            .map(|outpoint| TxInput {
+                txid: outpoint.get_txid().to_vec(),
+                vout: outpoint.get_vout(),
+                signature: vec![],
+                pub_key: wallet.get_pub_key().to_vec(),
+            })
+            .collect();
+        let mut tx = Transaction {
+            id: vec![],
+            vin,
+            vout: outputs,
+        };
+        tx.id = tx.hash();
+        tx.sign(blockchain, wallet.get_pkcs8());
         tx
     }
 
+    // Value of the spent outputs not paid out again, which the miner of the
+    // block collects. None when a spent output is unknown.
+    pub fn get_fee(&self, prev_out: impl Fn(&OutPoint) -> Option<UTXOEntry>) -> Option<i32> {
+        if self.is_coinbase() {
+            return Some(0);
+        }
+        let mut fee = 0;
+        for vin in &self.vin {
+            fee += prev_out(&vin.get_outpoint())?.get_value();
+        }
+        for out in &self.vout {
+            fee -= out.value;
+        }
+        Some(fee)
+    }
+
     fn trimmed_copy(&self) -> Transaction {
         let mut inputs = vec![];
         let mut outputs = vec![];
@@ -195,41 +334,58 @@ impl Transaction {
     }
 
     fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) {
-        let mut tx_copy = self.trimmed_copy();
-
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        for idx in 0..self.vin.len() {
+            let prev_out_option = utxo_set.get_entry(&self.vin[idx].get_outpoint());
+            if prev_out_option.is_none() {
                 panic!("Error: Previous tx is not correct");
             }
 
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
+            let prev_out = prev_out_option.unwrap();
+            let signature = self.signature_for(idx, &prev_out, pkcs8);
+            self.vin[idx].signature = signature;
+        }
+    }
 
-            let signature = ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
-            vin.signature = signature;
+    // Signs input `idx`, which spends `prev_out`, and sets its public key.
+    // Only needs the previous output, so it works without the chain.
+    pub fn sign_input(&mut self, idx: usize, prev_out: &UTXOEntry, pub_key: &[u8], pkcs8: &[u8]) {
+        self.vin[idx].signature = self.signature_for(idx, prev_out, pkcs8);
+        self.vin[idx].pub_key = pub_key.to_vec();
+    }
+
+    // The signature covers every outpoint and output, with the signed input
+    // holding the pub key hash of the output it spends.
+    fn signature_for(&self, idx: usize, prev_out: &UTXOEntry, pkcs8: &[u8]) -> Vec<u8> {
+        let mut tx_copy = self.trimmed_copy();
+        tx_copy.vin[idx].pub_key = prev_out.get_pub_key_hash().to_vec();
+        tx_copy.id = tx_copy.hash();
+        ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id())
+    }
+
+    // Sets the id once every input has its public key, hashing the
+    // transaction without signatures as utxo_transaction does.
+    pub fn update_id(&mut self) {
+        let mut tx_copy = self.clone();
+        for vin in tx_copy.vin.iter_mut() {
+            vin.signature = vec![];
         }
+        self.id = tx_copy.hash();
     }
 
-    pub fn verify(&self, blockchain: &Blockchain) -> bool {
+    pub fn verify_with(&self, prev_out: impl Fn(&OutPoint) -> Option<UTXOEntry>) -> bool {
         if self.is_coinbase() {
             return true;
         }
 
         let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-
-            if prev_tx_option.is_none() {
-                panic!("Error: previous tx is not correct");
-            }
+            let Some(prev_out) = prev_out(&vin.get_outpoint()) else {
+                return false;
+            };
 
-            let prev_tx = prev_tx_option.unwrap();
             tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = prev_out.get_pub_key_hash().to_vec();
             tx_copy.id = tx_copy.hash();
             tx_copy.vin[idx].pub_key = vec![];
 
@@ -247,7 +403,13 @@ impl Transaction {
     }
 
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].pub_key.is_empty()
+    }
+
+    // Spending an output twice would count its value twice.
+    pub fn has_duplicate_inputs(&self) -> bool {
+        let outpoints: HashSet<OutPoint> = self.vin.iter().map(|vin| vin.get_outpoint()).collect();
+        outpoints.len() != self.vin.len()
     }
 
     fn hash(&self) -> Vec<u8> {
@@ -260,3 +422,96 @@ impl Transaction {
         sha256_digest(tx_copy.serialize().as_slice())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        coin_selection::Strategy,
+        test_fixtures::{mature, mine, test_wallet},
+        wallets::{convert_address, Wallet},
+    };
+
+    // Funds `sender` with mature coinbase outputs worth 10 each.
+    fn funded_chain(sender: &Wallet) -> Blockchain {
+        let blockchain = Blockchain::temporary();
+        mine(&blockchain, sender, &[]);
+        mine(&blockchain, sender, &[]);
+        mature(&blockchain, sender);
+        blockchain
+    }
+
+    fn pay_many(
+        blockchain: &Blockchain,
+        sender: &Wallet,
+        recipients: &[(String, i32)],
+        coin_selection: &CoinSelection,
+    ) -> Transaction {
+        let mut wallets = Wallets::temporary(std::slice::from_ref(sender));
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        Transaction::utxo_transaction_many(
+            &mut wallets,
+            &sender.get_address(),
+            recipients,
+            &utxo_set,
+            coin_selection,
+        )
+    }
+
+    fn values(tx: &Transaction) -> Vec<(String, i32)> {
+        tx.get_vout()
+            .iter()
+            .map(|out| (convert_address(out.get_pub_key_hash()), out.get_value()))
+            .collect()
+    }
+
+    fn spending(inputs: &[(u8, usize)]) -> Transaction {
+        Transaction {
+            id: vec![],
+            vin: inputs
+                .iter()
+                .map(|(id, vout)| TxInput::new(&[*id; 32], *vout))
+                .collect(),
+            vout: vec![],
+        }
+    }
+
+    #[test]
+    fn detects_an_output_spent_twice() {
+        assert!(spending(&[(1, 0), (2, 0), (1, 0)]).has_duplicate_inputs());
+        assert!(!spending(&[(1, 0), (1, 1), (2, 0)]).has_duplicate_inputs());
+    }
+
+    #[test]
+    fn pays_every_recipient_with_one_change_output() {
+        let (sender, first, second) = (test_wallet(0), test_wallet(1), test_wallet(2));
+        let blockchain = funded_chain(&sender);
+        let recipients = vec![(first.get_address(), 3), (second.get_address(), 9)];
+        let coin_selection = CoinSelection {
+            strategy: Strategy::LargestFirst,
+            fee_per_input: 1,
+            inputs: vec![],
+        };
+        let tx = pay_many(&blockchain, &sender, &recipients, &coin_selection);
+
+        assert_eq!(tx.get_vin().len(), 2);
+        let mut expected = recipients.clone();
+        expected.push((sender.get_address(), 6));
+        assert_eq!(values(&tx), expected);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert_eq!(tx.get_fee(|outpoint| utxo_set.get_entry(outpoint)), Some(2));
+        let block = mine(&blockchain, &sender, std::slice::from_ref(&tx));
+        assert_eq!(block.get_transactions()[1].get_id(), tx.get_id());
+    }
+
+    #[test]
+    fn pays_the_total_exactly_without_change() {
+        let (sender, first, second) = (test_wallet(0), test_wallet(1), test_wallet(2));
+        let blockchain = funded_chain(&sender);
+        let recipients = vec![(first.get_address(), 4), (second.get_address(), 6)];
+        let tx = pay_many(&blockchain, &sender, &recipients, &CoinSelection::default());
+
+        assert_eq!(tx.get_vin().len(), 1);
+        assert_eq!(values(&tx), recipients);
+    }
+}
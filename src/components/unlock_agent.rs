@@ -0,0 +1,151 @@
+// Keeps the key of an unlocked wallet in the memory of a background process
+// rather than on disk. walletpassphrase starts the agent, which serves the key
+// on a unix socket at the wallet's unlock path, readable only by the owner,
+// and removes the socket and exits when the timeout passes or the wallet is
+// locked.
+#[cfg(unix)]
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    process::{self, Stdio},
+    thread,
+    time::Duration,
+};
+use std::{fs, io};
+
+use super::wallets::wallet_path;
+
+// Older versions wrote the derived key itself to this path, so anything that
+// is not a live agent's socket there is removed.
+const UNLOCK_EXTENSION: &str = "unlock";
+
+#[cfg(unix)]
+const GET_KEY: u8 = b'k';
+#[cfg(unix)]
+const LOCK: u8 = b'l';
+
+// Written by the agent once its socket accepts connections.
+#[cfg(unix)]
+const READY: &str = "ready";
+
+// Ends any earlier session of the wallet, then starts an agent holding `key`
+// for `timeout` seconds. The key goes through the agent's stdin so it never
+// shows up in the process list.
+#[cfg(unix)]
+pub fn spawn(name: &str, key: &[u8], timeout: u64) -> io::Result<()> {
+    lock(name);
+    let mut child = process::Command::new(env::current_exe()?)
+        .args(["unlockagent", name, &timeout.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().expect("stdin is piped").write_all(key)?;
+
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().expect("stdout is piped")).read_line(&mut line)?;
+    if line.trim_end() != READY {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(io::Error::other("the unlock agent did not start"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_name: &str, _key: &[u8], _timeout: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unlocking needs unix sockets",
+    ))
+}
+
+// The agent itself, run by spawn as the hidden unlockagent command.
+#[cfg(unix)]
+pub fn run(name: &str, timeout: u64) {
+    let mut key = vec![];
+    io::stdin()
+        .read_to_end(&mut key)
+        .expect("unable to read the key");
+
+    // Binds under a temporary name so the socket is never reachable before
+    // its permissions are restricted.
+    let path = wallet_path(name, UNLOCK_EXTENSION);
+    let tmp_path = path.with_extension("unlock.tmp");
+    let _ = fs::remove_file(&tmp_path);
+    let listener = UnixListener::bind(&tmp_path).expect("unable to bind the unlock socket");
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))
+        .expect("unable to restrict the unlock socket");
+    fs::rename(&tmp_path, &path).expect("unable to move the unlock socket");
+    println!("{}", READY);
+
+    let expiry_path = path.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(timeout));
+        let _ = fs::remove_file(expiry_path);
+        process::exit(0);
+    });
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        // A client that never sends its request can't block the others.
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+        let mut request = [0];
+        if stream.read_exact(&mut request).is_err() {
+            continue;
+        }
+        match request[0] {
+            GET_KEY => {
+                let _ = stream.write_all(&key);
+            }
+            LOCK => {
+                let _ = fs::remove_file(&path);
+                process::exit(0);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn run(_name: &str, _timeout: u64) {
+    unreachable!("the unlock agent is only started on unix");
+}
+
+// The key held by the wallet's agent, None when the wallet is locked.
+#[cfg(unix)]
+pub fn get_key(name: &str) -> Option<Vec<u8>> {
+    let path = wallet_path(name, UNLOCK_EXTENSION);
+    let Ok(mut stream) = UnixStream::connect(&path) else {
+        // A socket left by a killed agent, or a key file of an older version.
+        let _ = fs::remove_file(&path);
+        return None;
+    };
+    stream.write_all(&[GET_KEY]).ok()?;
+    let mut key = vec![];
+    stream.read_to_end(&mut key).ok()?;
+    Some(key).filter(|key| !key.is_empty())
+}
+
+#[cfg(not(unix))]
+pub fn get_key(name: &str) -> Option<Vec<u8>> {
+    let _ = fs::remove_file(wallet_path(name, UNLOCK_EXTENSION));
+    None
+}
+
+// Stops the wallet's agent and waits until it has removed its socket.
+pub fn lock(name: &str) {
+    let path = wallet_path(name, UNLOCK_EXTENSION);
+    #[cfg(unix)]
+    if let Ok(mut stream) = UnixStream::connect(&path) {
+        let _ = stream.write_all(&[LOCK]);
+        let _ = stream.read_to_end(&mut vec![]);
+    }
+    let _ = fs::remove_file(path);
+}
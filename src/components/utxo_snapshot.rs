@@ -0,0 +1,349 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use data_encoding::HEXLOWER;
+use ring::digest::{Context, SHA256};
+
+use super::{
+    blockchain::{Blockchain, ChainError},
+    blocks::BlockHeader,
+    config::GLOBAL_CONFIG,
+    memory_storage::MemoryStorage,
+    storage::{KvPair, Storage},
+    transaction::OUTPOINT_KEY_LEN,
+    utxoset::{UTXOEntry, UTXOSet, UTXO_TREE},
+};
+
+// File layout: MAGIC and a version byte, then the hashed payload and the
+// sha256 commitment of that payload. The payload is a u64 big-endian header
+// count and the headers of the active chain from genesis to the base block,
+// followed by a u64 big-endian entry count and the UTXO set at the base block
+// in key order. Headers and entries are each a u32 big-endian length and
+// their bincode encoding; entries are preceded by their outpoint key. Equal
+// chains and heights always produce the same commitment.
+const MAGIC: &[u8; 4] = b"MBCU";
+const FORMAT_VERSION: u8 = 1;
+
+// Guards against allocating for a corrupt length prefix.
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+pub struct SnapshotInfo {
+    pub base_hash: String,
+    pub base_height: usize,
+    pub utxo_count: usize,
+    pub commitment: String,
+}
+
+// Writes the UTXO set as of the active block at `height` (the tip when None).
+// Blocks above that height are rolled back in memory with their undo data.
+pub fn dump_utxo_snapshot(
+    blockchain: &Blockchain,
+    path: &Path,
+    height: Option<usize>,
+) -> Result<SnapshotInfo, Box<dyn Error>> {
+    let best_height = blockchain.get_best_height();
+    let base_height = height.unwrap_or(best_height);
+    if base_height > best_height {
+        return Err(format!("height {} is above the tip at {}", base_height, best_height).into());
+    }
+
+    let headers = (0..=base_height)
+        .map(|height| {
+            blockchain
+                .get_block_hash_by_height(height)
+                .and_then(|hash| blockchain.get_header(hash.as_bytes()))
+                .ok_or_else(|| format!("header at height {} is missing", height))
+        })
+        .collect::<Result<Vec<BlockHeader>, String>>()?;
+    let utxos = utxos_at(blockchain, base_height)?;
+
+    let mut writer = HashingWriter::new(BufWriter::new(File::create(path)?));
+    writer.inner.write_all(MAGIC)?;
+    writer.inner.write_all(&[FORMAT_VERSION])?;
+
+    writer.write_all(&(headers.len() as u64).to_be_bytes())?;
+    for header in &headers {
+        write_record(&mut writer, &header.serialize())?;
+    }
+    writer.write_all(&(utxos.len() as u64).to_be_bytes())?;
+    for (key, value) in &utxos {
+        writer.write_all(key)?;
+        write_record(&mut writer, value)?;
+    }
+
+    let commitment = writer.context.clone().finish();
+    writer.inner.write_all(commitment.as_ref())?;
+    writer.inner.flush()?;
+
+    let base = headers.last().unwrap();
+    Ok(SnapshotInfo {
+        base_hash: String::from(base.get_hash()),
+        base_height,
+        utxo_count: utxos.len(),
+        commitment: HEXLOWER.encode(commitment.as_ref()),
+    })
+}
+
+// Creates the chain in the current data directory from a snapshot whose
+// commitment matches `trusted_commitment`. Later blocks are synced from peers
+// as usual.
+pub fn load_utxo_snapshot(
+    path: &Path,
+    trusted_commitment: &str,
+) -> Result<(Blockchain, SnapshotInfo), Box<dyn Error>> {
+    load_utxo_snapshot_into(path, trusted_commitment, Blockchain::init_from_snapshot)
+}
+
+// Like `load_utxo_snapshot`, with `create_chain` creating the chain from the
+// headers and UTXO entries of the snapshot.
+fn load_utxo_snapshot_into(
+    path: &Path,
+    trusted_commitment: &str,
+    create_chain: impl FnOnce(&[BlockHeader], &[KvPair]) -> Result<Blockchain, ChainError>,
+) -> Result<(Blockchain, SnapshotInfo), Box<dyn Error>> {
+    let mut reader = HashingReader::new(BufReader::new(File::open(path)?));
+    let mut header = [0u8; 5];
+    reader.inner.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err("not a UTXO snapshot file".into());
+    }
+    if header[4] != FORMAT_VERSION {
+        return Err(format!("unsupported snapshot format version {}", header[4]).into());
+    }
+
+    let header_count = read_u64(&mut reader)?;
+    let mut headers: Vec<BlockHeader> = vec![];
+    for height in 0..header_count {
+        let header: BlockHeader = bincode::deserialize(&read_record(&mut reader)?)?;
+        let linked = match headers.last() {
+            Some(prev) => header.get_prev_block_hash() == prev.get_hash(),
+            None => true,
+        };
+        if header.get_height() as u64 != height || !linked {
+            return Err(format!("header at height {} does not extend the chain", height).into());
+        }
+        headers.push(header);
+    }
+
+    let utxo_count = read_u64(&mut reader)?;
+    let mut utxos: Vec<KvPair> = vec![];
+    for _ in 0..utxo_count {
+        let mut key = vec![0u8; OUTPOINT_KEY_LEN];
+        reader.read_exact(&mut key)?;
+        let value = read_record(&mut reader)?;
+        bincode::deserialize::<UTXOEntry>(&value)?;
+        if utxos.last().is_some_and(|(prev, _)| prev >= &key) {
+            return Err("UTXO entries are not in key order".into());
+        }
+        utxos.push((key, value));
+    }
+
+    let computed = HEXLOWER.encode(reader.context.clone().finish().as_ref());
+    let mut stored = [0u8; 32];
+    reader.inner.read_exact(&mut stored)?;
+    if HEXLOWER.encode(&stored) != computed {
+        return Err("snapshot is corrupted: commitment does not match its contents".into());
+    }
+    if !computed.eq_ignore_ascii_case(trusted_commitment) {
+        return Err(format!("snapshot commitment {} is not the trusted one", computed).into());
+    }
+
+    let network = GLOBAL_CONFIG.get_network();
+    if headers.first().map(|header| header.get_hash()) != Some(network.get_genesis_hash()) {
+        return Err(format!("snapshot is not for {}", network.get_name()).into());
+    }
+    let blockchain = create_chain(&headers, &utxos)?;
+    let base = headers.last().unwrap();
+    let info = SnapshotInfo {
+        base_hash: String::from(base.get_hash()),
+        base_height: base.get_height(),
+        utxo_count: utxos.len(),
+        commitment: computed,
+    };
+    Ok((blockchain, info))
+}
+
+// Copies the UTXO set into memory and disconnects the active blocks above
+// `height` from the copy.
+fn utxos_at(blockchain: &Blockchain, height: usize) -> Result<Vec<KvPair>, Box<dyn Error>> {
+    let memory = MemoryStorage::new();
+    for item in blockchain.get_storage().iter(UTXO_TREE) {
+        let (key, value) = item?;
+        memory.insert(UTXO_TREE, &key, &value)?;
+    }
+
+    let mut rollback = vec![];
+    for block in blockchain.range(height + 1..=usize::MAX).rev() {
+        let spent = blockchain
+            .get_undo(block.get_hash())
+            .ok_or_else(|| format!("undo data for block {} is missing", block.get_hash()))?;
+        rollback.push((block, spent));
+    }
+    if rollback.len() != blockchain.get_best_height() - height {
+        return Err(format!("blocks above height {} have been pruned", height).into());
+    }
+    memory.transaction(&[UTXO_TREE], &|tx| {
+        for (block, spent) in &rollback {
+            UTXOSet::disconnect(tx, block, spent)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(memory.iter(UTXO_TREE).collect::<Result<Vec<KvPair>, _>>()?)
+}
+
+fn write_record(writer: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_record(reader: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(format!("snapshot record of {} bytes is too large", len).into());
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    context: Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R> {
+    inner: R,
+    context: Context,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::components::test_fixtures::{mine, spend_coinbase, test_wallet, TempFile};
+
+    fn load(path: &Path, commitment: &str) -> Result<(Blockchain, SnapshotInfo), Box<dyn Error>> {
+        load_utxo_snapshot_into(path, commitment, Blockchain::temporary_from_snapshot)
+    }
+
+    fn utxo_entries(blockchain: &Blockchain) -> Vec<KvPair> {
+        blockchain
+            .get_storage()
+            .iter(UTXO_TREE)
+            .map(|item| item.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn load_restores_the_dumped_utxo_set() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (_, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        let file = TempFile::new("mbcu");
+        let dumped = dump_utxo_snapshot(&blockchain, &file.0, None).unwrap();
+        assert_eq!(dumped.base_hash, blockchain.get_latest_blk_hash());
+
+        let (loaded, info) = load(&file.0, &dumped.commitment).unwrap();
+        assert_eq!(info.base_height, blockchain.get_best_height());
+        assert_eq!(info.utxo_count, dumped.utxo_count);
+        assert_eq!(
+            loaded.get_latest_blk_hash(),
+            blockchain.get_latest_blk_hash()
+        );
+        assert_eq!(utxo_entries(&loaded), utxo_entries(&blockchain));
+
+        // The commitment only depends on the chain and the height.
+        let again = TempFile::new("mbcu");
+        let redumped = dump_utxo_snapshot(&loaded, &again.0, None).unwrap();
+        assert_eq!(redumped.commitment, dumped.commitment);
+    }
+
+    #[test]
+    fn dump_rolls_back_blocks_above_the_height() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let height = blockchain.get_best_height();
+        let tip_entries = utxo_entries(&blockchain);
+        mine(&blockchain, &miner, std::slice::from_ref(&spend));
+
+        let file = TempFile::new("mbcu");
+        let dumped = dump_utxo_snapshot(&blockchain, &file.0, Some(height)).unwrap();
+        assert_eq!(dumped.base_height, height);
+        let (loaded, _) = load(&file.0, &dumped.commitment).unwrap();
+        assert_eq!(loaded.get_best_height(), height);
+        assert_eq!(utxo_entries(&loaded), tip_entries);
+        assert!(UTXOSet::new(loaded).get_entry(&coinbase_out).is_some());
+        assert!(dump_utxo_snapshot(&blockchain, &file.0, Some(height + 2)).is_err());
+    }
+
+    #[test]
+    fn load_checks_the_commitment() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        mine(&blockchain, &miner, &[]);
+        let file = TempFile::new("mbcu");
+        let dumped = dump_utxo_snapshot(&blockchain, &file.0, None).unwrap();
+        assert!(load(&file.0, &"0".repeat(64)).is_err());
+
+        let mut bytes = fs::read(&file.0).unwrap();
+        let last_entry_byte = bytes.len() - 33;
+        bytes[last_entry_byte] ^= 1;
+        fs::write(&file.0, bytes).unwrap();
+        let error = load(&file.0, &dumped.commitment).err().unwrap();
+        assert!(error.to_string().contains("commitment does not match"));
+    }
+}
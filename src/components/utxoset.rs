@@ -1,10 +1,63 @@
 use std::collections::HashMap;
 
-use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
 
-use super::{blockchain::Blockchain, blocks::Block, transaction::TxOutput};
+use super::{
+    address_index::AddressIndex,
+    blockchain::{Blockchain, ChainError},
+    blocks::Block,
+    config::GLOBAL_CONFIG,
+    storage::StorageTransaction,
+    transaction::{OutPoint, TxOutput},
+};
 
-const UTXO_TREE: &str = "Chainstate";
+pub const UTXO_TREE: &str = "Chainstate";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UTXOEntry {
+    value: i32,
+    pub_key_hash: Vec<u8>,
+    height: usize,
+    is_coinbase: bool,
+}
+
+impl UTXOEntry {
+    pub fn new(output: &TxOutput, height: usize, is_coinbase: bool) -> Self {
+        Self {
+            value: output.get_value(),
+            pub_key_hash: output.get_pub_key_hash().to_vec(),
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn get_value(&self) -> i32 {
+        self.value
+    }
+
+    pub fn get_pub_key_hash(&self) -> &[u8] {
+        self.pub_key_hash.as_slice()
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+
+    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
+        self.pub_key_hash.eq(pub_key_hash)
+    }
+
+    pub fn is_mature(&self, spend_height: usize) -> bool {
+        if !self.is_coinbase {
+            return true;
+        }
+        spend_height >= self.height + GLOBAL_CONFIG.get_coinbase_maturity()
+    }
+}
 
 pub struct UTXOSet {
     blockchain: Blockchain,
@@ -19,111 +72,202 @@ impl UTXOSet {
         &self.blockchain
     }
 
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<usize>>) {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accumlated_amount: i32 = 0;
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+    // Outputs of the address that can be spent in the next block.
+    pub fn find_spendable_utxos(&self, pub_key_hash: &[u8]) -> Vec<(OutPoint, UTXOEntry)> {
+        let spend_height = self.blockchain.get_best_height() + 1;
+        self.find_address_outputs(pub_key_hash)
+            .into_iter()
+            .filter(|(_, out)| out.is_mature(spend_height))
+            .collect()
+    }
 
-        for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TxOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TxOutput");
-
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumlated_amount < amount {
-                    accumlated_amount += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<UTXOEntry> {
+        self.find_address_outputs(pub_key_hash)
+            .into_iter()
+            .map(|(_, out)| out)
+            .collect()
+    }
+
+    fn find_address_outputs(&self, pub_key_hash: &[u8]) -> Vec<(OutPoint, UTXOEntry)> {
+        if let Some(address_index) = AddressIndex::open(&self.blockchain) {
+            let mut utxos = vec![];
+            for outpoint in address_index.find_outpoints(pub_key_hash) {
+                if let Some(out) = self.get_entry(&outpoint) {
+                    utxos.push((outpoint, out));
                 }
             }
+            return utxos;
         }
-        (accumlated_amount, unspent_outputs)
-    }
 
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TxOutput> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let storage = self.blockchain.get_storage();
         let mut utxos = vec![];
-        for item in utxo_tree.iter() {
-            let (_, v) = item.unwrap();
-            let outs: Vec<TxOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TxOutput");
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone());
-                }
+        for item in storage.iter(UTXO_TREE) {
+            let (k, v) = item.unwrap();
+            let out: UTXOEntry =
+                bincode::deserialize(v.as_slice()).expect("unable to deserialize UTXOEntry");
+            if out.is_locked_with_key(pub_key_hash) {
+                utxos.push((OutPoint::from_key(k.as_slice()), out));
             }
         }
         utxos
     }
 
+    pub fn get_entry(&self, outpoint: &OutPoint) -> Option<UTXOEntry> {
+        let storage = self.blockchain.get_storage();
+        let out_bytes = storage.get(UTXO_TREE, &outpoint.to_key()).unwrap()?;
+        Some(bincode::deserialize(out_bytes.as_slice()).expect("unable to deserialize UTXOEntry"))
+    }
+
     pub fn count_transaction(&self) -> i32 {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let storage = self.blockchain.get_storage();
         let mut count = 0;
-        for _ in utxo_tree.iter() {
-            count += 1;
+        let mut last_txid = vec![];
+        for item in storage.iter(UTXO_TREE) {
+            let (k, _) = item.unwrap();
+            let outpoint = OutPoint::from_key(k.as_slice());
+            if outpoint.get_txid() != last_txid.as_slice() {
+                count += 1;
+                last_txid = outpoint.get_txid().to_vec();
+            }
         }
         count
     }
 
     pub fn reindex(&self) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let _ = utxo_tree.clear().unwrap();
+        if self.blockchain.is_pruned() {
+            panic!("Unable to rebuild the UTXO set: old block bodies have been pruned");
+        }
+        let storage = self.blockchain.get_storage();
+        storage.clear(UTXO_TREE).unwrap();
 
         let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = bincode::serialize(outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
+        for (outpoint, out) in &utxo_map {
+            let value = bincode::serialize(out).unwrap();
+            storage
+                .insert(UTXO_TREE, &outpoint.to_key(), &value)
+                .unwrap();
+        }
+        self.blockchain
+            .set_utxo_best_block(&self.blockchain.get_latest_blk_hash());
+
+        if AddressIndex::is_enabled() {
+            AddressIndex::new(self.blockchain.clone()).reindex();
         }
     }
 
-    pub fn update(&self, block: &Block) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+    pub fn update(
+        tx_storage: &dyn StorageTransaction,
+        block: &Block,
+    ) -> Result<HashMap<OutPoint, UTXOEntry>, ChainError> {
+        let mut spent = HashMap::new();
         for tx in block.get_transactions() {
-            if tx.is_coinbase() == false {
+            if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let out_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TxOutput> = bincode::deserialize(&out_bytes)
-                        .expect("unable to deserialize the TxOutput");
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
-                        }
-                    }
-                    if updated_outs.len() == 0 {
-                        let _ = utxo_tree.remove(vin.get_txid()).unwrap();
-                    } else {
-                        let out_bytes = bincode::serialize(&updated_outs)
-                            .expect("unable to serialize TxOutput");
-                        utxo_tree.insert(vin.get_txid(), out_bytes).unwrap();
-                    }
+                    let outpoint = vin.get_outpoint();
+                    let Some(out_bytes) = tx_storage.remove(UTXO_TREE, &outpoint.to_key())? else {
+                        return Err(ChainError::MissingInput(outpoint));
+                    };
+                    let out: UTXOEntry = bincode::deserialize(out_bytes.as_slice())
+                        .expect("unable to deserialize UTXOEntry");
+                    spent.insert(outpoint, out);
                 }
             }
 
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone());
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                let entry = UTXOEntry::new(out, block.get_height(), tx.is_coinbase());
+                let out_bytes = bincode::serialize(&entry).expect("unable to serialize UTXOEntry");
+                let outpoint = OutPoint::new(tx.get_id(), idx);
+                tx_storage.insert(UTXO_TREE, &outpoint.to_key(), &out_bytes)?;
+            }
+        }
+        Ok(spent)
+    }
+
+    pub fn disconnect(
+        tx_storage: &dyn StorageTransaction,
+        block: &Block,
+        spent: &HashMap<OutPoint, UTXOEntry>,
+    ) -> Result<(), ChainError> {
+        for tx in block.get_transactions().iter().rev() {
+            for idx in 0..tx.get_vout().len() {
+                tx_storage.remove(UTXO_TREE, &OutPoint::new(tx.get_id(), idx).to_key())?;
+            }
+
+            if tx.is_coinbase() {
+                continue;
             }
+            for vin in tx.get_vin() {
+                let outpoint = vin.get_outpoint();
+                let Some(out) = spent.get(&outpoint) else {
+                    return Err(ChainError::Corrupted(format!(
+                        "undo data of block {} is incomplete",
+                        block.get_hash()
+                    )));
+                };
+                let out_bytes = bincode::serialize(out).expect("unable to serialize UTXOEntry");
+                tx_storage.insert(UTXO_TREE, &outpoint.to_key(), &out_bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        test_fixtures::{mine, spend_coinbase, test_wallet},
+        transaction::Transaction,
+        wallets::get_pub_key_hash,
+    };
+
+    #[test]
+    fn connected_blocks_move_spent_outputs() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert!(utxo_set.get_entry(&coinbase_out).unwrap().is_coinbase());
+
+        let block = mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        assert!(utxo_set.get_entry(&coinbase_out).is_none());
+        let paid = utxo_set
+            .get_entry(&OutPoint::new(spend.get_id(), 0))
+            .unwrap();
+        assert_eq!(paid.get_value(), 4);
+        assert_eq!(paid.get_height(), block.get_height());
+        assert!(!paid.is_coinbase());
+
+        let recipient_utxos = utxo_set.find_utxo(&get_pub_key_hash(&recipient.get_address()));
+        assert_eq!(recipient_utxos.len(), 1);
+    }
+
+    #[test]
+    fn reorg_rebuilds_the_utxo_set() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        let stale = mine(&blockchain, &miner, &[]);
+        let stale_out = OutPoint::new(stale.get_transactions()[0].get_id(), 0);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert!(utxo_set.get_entry(&stale_out).is_some());
+
+        // Another miner, so the fork's coinbases differ from the stale one.
+        let fork_miner = test_wallet(1);
+        let mut prev_block_hash = genesis_hash;
+        let mut fork_outs = vec![];
+        for height in 1..=2 {
+            let coinbase = Transaction::coinbase_tx(&fork_miner.get_address(), height, 0);
+            fork_outs.push(OutPoint::new(coinbase.get_id(), 0));
+            let block = Block::new(prev_block_hash, &[coinbase], height);
+            blockchain.add_block(&block).unwrap();
+            prev_block_hash = String::from(block.get_hash());
+        }
 
-            let out_bytes = bincode::serialize(&new_outputs).expect("unable to serialize TxOutput");
-            utxo_tree.insert(tx.get_id(), out_bytes).unwrap();
+        assert_eq!(blockchain.get_latest_blk_hash(), prev_block_hash);
+        assert!(utxo_set.get_entry(&stale_out).is_none());
+        for outpoint in &fork_outs {
+            assert_eq!(utxo_set.get_entry(outpoint).unwrap().get_value(), 10);
         }
     }
 }
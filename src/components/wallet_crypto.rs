@@ -0,0 +1,130 @@
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+pub const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+// scrypt cost: 2^15 iterations with r = 8 takes about 32 MiB and a fraction
+// of a second, once per unlock.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Stored next to the encrypted keys so the cost can be raised later without
+// breaking existing wallets.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl KdfParams {
+    pub fn generate() -> Self {
+        Self {
+            salt: random_bytes(SALT_LEN),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+
+    pub fn derive_key(&self, passphrase: &str) -> Vec<u8> {
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, KEY_LEN)
+            .expect("invalid scrypt parameters in wallet file");
+        let mut key = vec![0; KEY_LEN];
+        scrypt::scrypt(passphrase.as_bytes(), &self.salt, &params, &mut key)
+            .expect("scrypt output length is valid");
+        key
+    }
+}
+
+// Encrypts `plaintext` with a random nonce and returns the nonce followed by
+// the ciphertext. `aad` is authenticated but not encrypted.
+pub fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new_from_slice(key).expect("key has the right length");
+    let nonce = random_bytes(NONCE_LEN);
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("encryption failed");
+    let mut sealed = nonce;
+    sealed.extend(ciphertext);
+    sealed
+}
+
+// Returns None when the key is wrong or the data was tampered with.
+pub fn open(key: &[u8], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let cipher = XChaCha20Poly1305::new_from_slice(key).ok()?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("unable to read random bytes");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cheap scrypt cost keeps the tests fast.
+    fn test_params() -> KdfParams {
+        KdfParams {
+            log_n: 4,
+            ..KdfParams::generate()
+        }
+    }
+
+    #[test]
+    fn opens_only_with_the_passphrase_key() {
+        let params = test_params();
+        let key = params.derive_key("correct horse");
+        let sealed = seal(&key, b"private key", b"address");
+
+        assert_eq!(open(&key, &sealed, b"address").unwrap(), b"private key");
+        let wrong_key = params.derive_key("wrong horse");
+        assert!(open(&wrong_key, &sealed, b"address").is_none());
+        // The same passphrase under another salt derives another key.
+        let other_salt_key = test_params().derive_key("correct horse");
+        assert!(open(&other_salt_key, &sealed, b"address").is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_data() {
+        let key = test_params().derive_key("correct horse");
+        let sealed = seal(&key, b"private key", b"address");
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&key, &tampered, b"address").is_none());
+        assert!(open(&key, &sealed, b"another address").is_none());
+        assert!(open(&key, &sealed[..NONCE_LEN - 1], b"address").is_none());
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    blockchain::Blockchain,
+    blocks::Block,
+    config::GLOBAL_CONFIG,
+    helpers::current_timestamp,
+    transaction::{OutPoint, Transaction},
+    utxoset::UTXOEntry,
+    wallets::{wallet_path, write_private_file},
+};
+
+const HISTORY_MAGIC: &[u8; 4] = b"MBWH";
+const HISTORY_VERSION: u8 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WalletTx {
+    txid: Vec<u8>,
+    // Hash and height of the block holding the transaction, None while it is
+    // unconfirmed.
+    block: Option<(String, usize)>,
+    timestamp: i64,
+    is_coinbase: bool,
+    // Outpoints the transaction spends, to notice when another transaction
+    // spends them first.
+    inputs: Vec<OutPoint>,
+    received: i32,
+    sent: i32,
+}
+
+impl WalletTx {
+    pub fn get_txid(&self) -> &[u8] {
+        &self.txid
+    }
+
+    pub fn get_height(&self) -> Option<usize> {
+        self.block.as_ref().map(|(_, height)| *height)
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    // Value of the outputs paying the wallet.
+    pub fn get_received(&self) -> i32 {
+        self.received
+    }
+
+    // Value of the wallet's outputs spent by the transaction.
+    pub fn get_sent(&self) -> i32 {
+        self.sent
+    }
+
+    pub fn get_confirmations(&self, best_height: usize) -> usize {
+        self.get_height()
+            .map_or(0, |height| best_height.saturating_sub(height) + 1)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HistoryFile {
+    // Active block the history was last synced to.
+    synced_to: Option<String>,
+    txs: Vec<WalletTx>,
+}
+
+#[derive(Default)]
+pub struct SyncReport {
+    pub connected: usize,
+    pub disconnected: usize,
+    // Blocks whose bodies have been pruned, so their transactions are missing
+    // from the history.
+    pub pruned: usize,
+}
+
+// Transactions touching a wallet on the selected network, kept next to the
+// wallet file. The history catches up with the active chain in sync, undoing
+// blocks that were disconnected since the last sync first.
+pub struct WalletHistory {
+    path: PathBuf,
+    file: HistoryFile,
+}
+
+impl WalletHistory {
+    pub fn load(wallet_name: &str) -> Self {
+        let extension = format!("{}.history", GLOBAL_CONFIG.get_network().get_name());
+        let path = wallet_path(wallet_name, &extension);
+        let file = match fs::read(&path) {
+            Ok(bytes) => match bytes.strip_prefix(HISTORY_MAGIC) {
+                Some([HISTORY_VERSION, data @ ..]) => bincode::deserialize(data)
+                    .unwrap_or_else(|e| panic!("unable to read {}: {}", path.display(), e)),
+                _ => panic!("{} is not a wallet history file", path.display()),
+            },
+            Err(_) => HistoryFile::default(),
+        };
+        Self { path, file }
+    }
+
+    // Forgets the confirmed transactions so the next sync scans the chain
+    // from genesis, after keys were added that may have older transactions.
+    pub fn reset(&mut self) {
+        self.file.synced_to = None;
+        self.file.txs.retain(|tx| tx.block.is_none());
+        self.save();
+    }
+
+    pub fn sync(&mut self, blockchain: &Blockchain, mine: &HashSet<Vec<u8>>) -> SyncReport {
+        let mut report = SyncReport::default();
+        let mut next_height = 0;
+        while let Some(hash) = self.file.synced_to.clone() {
+            let Some(header) = blockchain.get_header(hash.as_bytes()) else {
+                // Synced to a chain this node doesn't know, start over.
+                self.reset();
+                break;
+            };
+            let active = blockchain.get_block_hash_by_height(header.get_height());
+            if active.as_deref() == Some(hash.as_str()) {
+                next_height = header.get_height() + 1;
+                break;
+            }
+            self.disconnect(&hash);
+            report.disconnected += 1;
+            self.file.synced_to = Some(header.get_prev_block_hash());
+        }
+
+        let best_height = blockchain.get_best_height();
+        for height in next_height..=best_height {
+            match blockchain.get_block_by_height(height) {
+                Some(block) => {
+                    self.connect(blockchain, &block, mine);
+                    report.connected += 1;
+                }
+                None => report.pruned += 1,
+            }
+        }
+        if report.connected + report.disconnected + report.pruned > 0 {
+            self.file.synced_to = blockchain.get_block_hash_by_height(best_height);
+            self.save();
+        }
+        report
+    }
+
+    // Records a transaction the wallet broadcast. `prev_out` returns the
+    // outputs it spends.
+    pub fn add_unconfirmed(
+        &mut self,
+        tx: &Transaction,
+        prev_out: impl Fn(&OutPoint) -> Option<UTXOEntry>,
+        mine: &HashSet<Vec<u8>>,
+    ) {
+        let spent: HashMap<OutPoint, UTXOEntry> = tx
+            .get_vin()
+            .iter()
+            .filter_map(|vin| Some((vin.get_outpoint(), prev_out(&vin.get_outpoint())?)))
+            .collect();
+        if let Some(wallet_tx) = to_wallet_tx(tx, &spent, mine, current_timestamp()) {
+            self.file.txs.push(wallet_tx);
+            self.save();
+        }
+    }
+
+    // Confirmed and unconfirmed balance change of the wallet's transactions.
+    pub fn get_balances(&self) -> (i32, i32) {
+        let mut confirmed = 0;
+        let mut unconfirmed = 0;
+        for tx in &self.file.txs {
+            match tx.block {
+                Some(_) => confirmed += tx.received - tx.sent,
+                None => unconfirmed += tx.received - tx.sent,
+            }
+        }
+        (confirmed, unconfirmed)
+    }
+
+    // Newest first, unconfirmed transactions before confirmed ones.
+    pub fn get_transactions(&self) -> Vec<&WalletTx> {
+        let mut txs: Vec<&WalletTx> = self.file.txs.iter().collect();
+        txs.sort_by_key(|tx| (tx.get_height().unwrap_or(usize::MAX), tx.timestamp));
+        txs.reverse();
+        txs
+    }
+
+    fn connect(&mut self, blockchain: &Blockchain, block: &Block, mine: &HashSet<Vec<u8>>) {
+        let spent = blockchain.get_spent_outputs(block);
+        let mut spenders: HashMap<OutPoint, &[u8]> = HashMap::new();
+        for tx in block.get_transactions() {
+            for vin in tx.get_vin() {
+                spenders.insert(vin.get_outpoint(), tx.get_id());
+            }
+
+            let Some(mut wallet_tx) = to_wallet_tx(tx, &spent, mine, block.get_timestamp()) else {
+                continue;
+            };
+            wallet_tx.block = Some((String::from(block.get_hash()), block.get_height()));
+            match self.file.txs.iter_mut().find(|known| known.txid == tx.get_id()) {
+                Some(known) => *known = wallet_tx,
+                None => self.file.txs.push(wallet_tx),
+            }
+        }
+
+        // Unconfirmed transactions whose inputs this block spent elsewhere
+        // can never confirm.
+        self.file.txs.retain(|tx| {
+            tx.block.is_some()
+                || !tx.inputs.iter().any(|input| {
+                    spenders
+                        .get(input)
+                        .is_some_and(|spender| *spender != tx.txid.as_slice())
+                })
+        });
+    }
+
+    // Transactions of a disconnected block become unconfirmed again, except
+    // coinbase transactions which only exist in their block.
+    fn disconnect(&mut self, block_hash: &str) {
+        self.file.txs.retain_mut(|tx| {
+            if tx.block.as_ref().is_none_or(|(hash, _)| hash != block_hash) {
+                return true;
+            }
+            tx.block = None;
+            !tx.is_coinbase
+        });
+    }
+
+    fn save(&self) {
+        let mut bytes = HISTORY_MAGIC.to_vec();
+        bytes.push(HISTORY_VERSION);
+        bytes.extend(bincode::serialize(&self.file).expect("unable to serialize the history"));
+        write_private_file(&self.path, &bytes);
+    }
+}
+
+// None when the transaction neither pays nor spends the wallet.
+fn to_wallet_tx(
+    tx: &Transaction,
+    spent: &HashMap<OutPoint, UTXOEntry>,
+    mine: &HashSet<Vec<u8>>,
+    timestamp: i64,
+) -> Option<WalletTx> {
+    let mut touched = false;
+    let mut received = 0;
+    for out in tx.get_vout() {
+        if mine.contains(out.get_pub_key_hash()) {
+            touched = true;
+            received += out.get_value();
+        }
+    }
+
+    let mut sent = 0;
+    let mut inputs = vec![];
+    if !tx.is_coinbase() {
+        for vin in tx.get_vin() {
+            let outpoint = vin.get_outpoint();
+            if let Some(entry) = spent.get(&outpoint) {
+                if mine.contains(entry.get_pub_key_hash()) {
+                    touched = true;
+                    sent += entry.get_value();
+                }
+            }
+            inputs.push(outpoint);
+        }
+    }
+
+    touched.then(|| WalletTx {
+        txid: tx.get_id().to_vec(),
+        block: None,
+        timestamp,
+        is_coinbase: tx.is_coinbase(),
+        inputs,
+        received,
+        sent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{
+        test_fixtures::{mine, spend_coinbase, test_wallet, TempFile},
+        transaction::TxOutput,
+        utxoset::UTXOSet,
+        wallets::{get_pub_key_hash, Wallet},
+    };
+
+    fn empty_history(file: &TempFile) -> WalletHistory {
+        WalletHistory {
+            path: file.0.clone(),
+            file: HistoryFile::default(),
+        }
+    }
+
+    fn keys(wallet: &Wallet) -> HashSet<Vec<u8>> {
+        HashSet::from([get_pub_key_hash(&wallet.get_address())])
+    }
+
+    // Adds two empty blocks on top of `base`, mined by a wallet the tests
+    // don't track so the coinbases differ from the stale blocks'.
+    fn add_longer_fork(blockchain: &Blockchain, base: String) {
+        let fork_miner = test_wallet(2);
+        let base_height = blockchain.get_header(base.as_bytes()).unwrap().get_height();
+        let mut prev_block_hash = base;
+        for height in base_height + 1..=base_height + 2 {
+            let coinbase = Transaction::coinbase_tx(&fork_miner.get_address(), height, 0);
+            let block = Block::new(prev_block_hash, &[coinbase], height);
+            blockchain.add_block(&block).unwrap();
+            prev_block_hash = String::from(block.get_hash());
+        }
+    }
+
+    #[test]
+    fn sync_records_received_and_sent_values() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (_, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let file = TempFile::new("history");
+        let mut history = empty_history(&file);
+        let report = history.sync(&blockchain, &keys(&recipient));
+        assert_eq!(report.connected, blockchain.get_best_height() + 1);
+        assert!(history.get_transactions().is_empty());
+
+        mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        let report = history.sync(&blockchain, &keys(&recipient));
+        assert_eq!((report.connected, report.disconnected), (1, 0));
+        assert_eq!(history.get_balances(), (4, 0));
+        let txs = history.get_transactions();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].get_txid(), spend.get_id());
+        assert_eq!(txs[0].get_confirmations(blockchain.get_best_height()), 1);
+
+        let miner_file = TempFile::new("history");
+        let mut miner_history = empty_history(&miner_file);
+        miner_history.sync(&blockchain, &keys(&miner));
+        let sent = miner_history
+            .get_transactions()
+            .into_iter()
+            .find(|tx| tx.get_txid() == spend.get_id())
+            .unwrap();
+        assert_eq!((sent.get_received(), sent.get_sent()), (6, 10));
+    }
+
+    #[test]
+    fn drops_unconfirmed_transactions_whose_inputs_are_spent_elsewhere() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (coinbase_out, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let file = TempFile::new("history");
+        let mut history = empty_history(&file);
+        history.sync(&blockchain, &keys(&miner));
+        let (confirmed, _) = history.get_balances();
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let prev_out = |outpoint: &OutPoint| utxo_set.get_entry(outpoint);
+        history.add_unconfirmed(&spend, prev_out, &keys(&miner));
+        assert_eq!(history.get_balances(), (confirmed, -4));
+
+        let double_spend = Transaction::spend(
+            &blockchain,
+            &miner,
+            &[coinbase_out],
+            vec![TxOutput::new(10, &miner.get_address())],
+        );
+        mine(&blockchain, &miner, &[double_spend]);
+        history.sync(&blockchain, &keys(&miner));
+        assert_eq!(history.get_balances(), (confirmed + 10, 0));
+        assert!(history
+            .get_transactions()
+            .iter()
+            .all(|tx| tx.get_txid() != spend.get_id()));
+    }
+
+    #[test]
+    fn disconnected_transactions_become_unconfirmed() {
+        let (miner, recipient) = (test_wallet(0), test_wallet(1));
+        let blockchain = Blockchain::temporary();
+        let (_, spend) = spend_coinbase(&blockchain, &miner, &recipient);
+        let fork_base = blockchain.get_latest_blk_hash();
+        mine(&blockchain, &miner, std::slice::from_ref(&spend));
+        let file = TempFile::new("history");
+        let mut history = empty_history(&file);
+        history.sync(&blockchain, &keys(&recipient));
+        assert_eq!(history.get_balances(), (4, 0));
+
+        add_longer_fork(&blockchain, fork_base);
+        let report = history.sync(&blockchain, &keys(&recipient));
+        assert_eq!((report.connected, report.disconnected), (2, 1));
+        assert_eq!(history.get_balances(), (0, 4));
+        assert_eq!(history.get_transactions()[0].get_height(), None);
+    }
+
+    #[test]
+    fn disconnected_coinbase_transactions_are_dropped() {
+        let miner = test_wallet(0);
+        let blockchain = Blockchain::temporary();
+        let genesis_hash = blockchain.get_latest_blk_hash();
+        mine(&blockchain, &miner, &[]);
+        let file = TempFile::new("history");
+        let mut history = empty_history(&file);
+        history.sync(&blockchain, &keys(&miner));
+        assert_eq!(history.get_balances(), (10, 0));
+
+        add_longer_fork(&blockchain, genesis_hash);
+        let report = history.sync(&blockchain, &keys(&miner));
+        assert_eq!((report.connected, report.disconnected), (2, 1));
+        assert_eq!(history.get_balances(), (0, 0));
+        assert!(history.get_transactions().is_empty());
+    }
+}
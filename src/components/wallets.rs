@@ -1,56 +1,167 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env::current_dir,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Read, Write},
+    error::Error,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
 
-use super::helpers::{
-    base58_decode, base58_encode, create_key_pair, ripemd160_digest, sha256_digest,
+use super::{
+    hd_keys::{
+        derive_pkcs8, entropy_to_seed, generate_entropy, to_mnemonic, CHANGE_CHAIN, GAP_LIMIT,
+        RECEIVE_CHAIN,
+    },
+    helpers::{base58_decode, base58_encode, ripemd160_digest, sha256_digest},
+    unlock_agent,
+    wallet_crypto::{open, seal, KdfParams},
 };
 
 const VERSION: u8 = 0x00;
 
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
 
-pub const WALLET_FILE: &str = "wallet.dat";
+// Prefix of an encoded private key, as in Bitcoin's WIF.
+const PRIVATE_KEY_VERSION: u8 = 0x80;
+
+// New kinds go at the end, the position is part of the wallet file format.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressKind {
+    Receive,
+    // Tracked for its balance only. The wallet has neither its private nor
+    // its public key.
+    WatchOnly,
+    // Receives the change of the wallet's own transactions.
+    Change,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Wallet {
     pkcs8: Vec<u8>,
     pub_key: Vec<u8>,
+    pub_key_hash: Vec<u8>,
+    label: String,
+    kind: AddressKind,
+}
+
+// Layout of Wallet before watch-only addresses.
+#[derive(Deserialize)]
+struct WalletV2 {
+    pkcs8: Vec<u8>,
+    pub_key: Vec<u8>,
+    label: String,
+}
+
+// Layout of Wallet before labels.
+#[derive(Deserialize)]
+struct WalletV1 {
+    pkcs8: Vec<u8>,
+    pub_key: Vec<u8>,
+}
+
+impl From<WalletV2> for Wallet {
+    fn from(wallet: WalletV2) -> Self {
+        Self {
+            pub_key_hash: hash_pub_key(&wallet.pub_key),
+            pkcs8: wallet.pkcs8,
+            pub_key: wallet.pub_key,
+            label: wallet.label,
+            kind: AddressKind::Receive,
+        }
+    }
+}
+
+impl From<WalletV1> for Wallet {
+    fn from(wallet: WalletV1) -> Self {
+        Wallet::from(WalletV2 {
+            pkcs8: wallet.pkcs8,
+            pub_key: wallet.pub_key,
+            label: String::new(),
+        })
+    }
 }
 
 impl Wallet {
-    pub fn new() -> Self {
-        let pkcs8 = create_key_pair();
+    pub fn from_pkcs8(pkcs8: Vec<u8>) -> Self {
         let rng = ring::rand::SystemRandom::new();
         let key_pair =
             EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng).unwrap();
         let pub_key = key_pair.public_key().as_ref().to_vec();
-        Self { pkcs8, pub_key }
+        Self {
+            pkcs8,
+            pub_key_hash: hash_pub_key(&pub_key),
+            pub_key,
+            label: String::new(),
+            kind: AddressKind::Receive,
+        }
+    }
+
+    pub fn watch_only(address: &str) -> Self {
+        Self {
+            pkcs8: vec![],
+            pub_key: vec![],
+            pub_key_hash: get_pub_key_hash(address),
+            label: String::new(),
+            kind: AddressKind::WatchOnly,
+        }
     }
 
     pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(self.pub_key.as_slice());
-        let mut payload: Vec<u8> = vec![];
-        payload.push(VERSION);
-        payload.extend(pub_key_hash.as_slice());
-        let chekshum = checksum(payload.as_slice());
-        payload.extend(chekshum.as_slice());
-        base58_encode(payload.as_slice())
+        convert_address(&self.pub_key_hash)
     }
 
     pub fn get_pub_key(&self) -> &[u8] {
         self.pub_key.as_slice()
     }
 
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+
+    // The key of a wallet that was never encrypted, for signing in tests.
+    #[cfg(test)]
     pub fn get_pkcs8(&self) -> &[u8] {
         self.pkcs8.as_slice()
     }
+
+    pub fn get_kind(&self) -> AddressKind {
+        self.kind
+    }
+}
+
+// Encodes a PKCS#8 private key as base58 of PRIVATE_KEY_VERSION, the DER
+// document and a 4 byte double sha256 checksum of both.
+pub fn encode_private_key(pkcs8: &[u8]) -> String {
+    let mut payload = vec![PRIVATE_KEY_VERSION];
+    payload.extend(pkcs8);
+    let checksum = checksum(&payload);
+    payload.extend(checksum);
+    base58_encode(&payload)
+}
+
+// Returns the PKCS#8 document of a key from encode_private_key.
+pub fn decode_private_key(encoded: &str) -> Result<Vec<u8>, WalletError> {
+    let payload = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|_| WalletError::InvalidPrivateKey)?;
+    if payload.len() <= 1 + ADDRESS_CHECK_SUM_LEN || payload[0] != PRIVATE_KEY_VERSION {
+        return Err(WalletError::InvalidPrivateKey);
+    }
+    let (data, actual_checksum) = payload.split_at(payload.len() - ADDRESS_CHECK_SUM_LEN);
+    if checksum(data) != actual_checksum {
+        return Err(WalletError::InvalidPrivateKey);
+    }
+    let pkcs8 = data[1..].to_vec();
+    let rng = ring::rand::SystemRandom::new();
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+        .map_err(|_| WalletError::InvalidPrivateKey)?;
+    Ok(pkcs8)
 }
 
 pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
@@ -77,6 +188,11 @@ pub fn validate_address(address: &str) -> bool {
     actual_checksum.eq(target_checksum.as_slice())
 }
 
+pub fn get_pub_key_hash(address: &str) -> Vec<u8> {
+    let payload = base58_decode(address);
+    payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec()
+}
+
 pub fn convert_address(pub_key_hash: &[u8]) -> String {
     let mut payload: Vec<u8> = vec![];
     payload.push(VERSION);
@@ -88,37 +204,488 @@ pub fn convert_address(pub_key_hash: &[u8]) -> String {
 
 // wallets
 
+// The default wallet lives in wallet.dat, named wallets in WALLETS_DIR as
+// <name>.dat. Every file extension below follows the same rule.
+pub const DEFAULT_WALLET: &str = "default";
+const WALLETS_DIR: &str = "wallets";
+const WALLET_EXTENSION: &str = "dat";
+
+// Names of the loaded named wallets, one per line. The default wallet is
+// always loaded.
+const LOADED_WALLETS_FILE: &str = "loaded";
+
+const MAX_WALLET_NAME_LEN: usize = 64;
+
+const WALLET_FILE_MAGIC: &[u8; 4] = b"MBWL";
+
+// Written after WALLET_FILE_MAGIC. Version 1 files have no version byte and
+// start directly with a bincode Option tag, which is 0 or 1.
+const WALLET_FILE_VERSION: u8 = 5;
+const WALLET_FILE_VERSION_WATCH_ONLY: u8 = 4;
+const WALLET_FILE_VERSION_LABELS: u8 = 3;
+const WALLET_FILE_VERSION_HD: u8 = 2;
+
+// A passphrase check value encrypted with the wallet key.
+const CHECK_PLAINTEXT: &[u8] = b"mini-blockchain wallet";
+
+// Associated data of the sealed HD seed entropy.
+const HD_SEED_AAD: &[u8] = b"hd seed";
+
+#[derive(Debug)]
+pub enum WalletError {
+    Locked,
+    NotEncrypted,
+    AlreadyEncrypted,
+    WrongPassphrase,
+    UnknownAddress(String),
+    NotEmpty,
+    InvalidMnemonic(bip39::Error),
+    InvalidWalletName(String),
+    WalletExists(String),
+    WalletNotFound(String),
+    NotLoaded(String),
+    AlreadyLoaded(String),
+    DefaultWallet,
+    WatchOnly(String),
+    AddressExists(String),
+    InvalidPrivateKey,
+    Unlock(io::Error),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::Locked => {
+                write!(f, "wallet is locked, unlock it with walletpassphrase first")
+            }
+            WalletError::NotEncrypted => write!(f, "wallet is not encrypted"),
+            WalletError::AlreadyEncrypted => write!(f, "wallet is already encrypted"),
+            WalletError::WrongPassphrase => write!(f, "the passphrase is incorrect"),
+            WalletError::UnknownAddress(address) => {
+                write!(f, "address {} is not in the wallet", address)
+            }
+            WalletError::NotEmpty => {
+                write!(f, "the wallet already has keys, restore into a new wallet")
+            }
+            WalletError::InvalidMnemonic(e) => write!(f, "invalid mnemonic: {}", e),
+            WalletError::InvalidWalletName(name) => write!(
+                f,
+                "invalid wallet name {:?}, use up to {} letters, digits, '-' or '_'",
+                name, MAX_WALLET_NAME_LEN
+            ),
+            WalletError::WalletExists(name) => write!(f, "wallet {} already exists", name),
+            WalletError::WalletNotFound(name) => write!(f, "wallet {} does not exist", name),
+            WalletError::NotLoaded(name) => {
+                write!(f, "wallet {} is not loaded, load it with loadwallet", name)
+            }
+            WalletError::AlreadyLoaded(name) => write!(f, "wallet {} is already loaded", name),
+            WalletError::DefaultWallet => write!(f, "the default wallet is always loaded"),
+            WalletError::WatchOnly(address) => {
+                write!(f, "address {} is watch-only, the wallet has no key for it", address)
+            }
+            WalletError::AddressExists(address) => {
+                write!(f, "address {} is already in the wallet", address)
+            }
+            WalletError::InvalidPrivateKey => write!(f, "the private key is not valid"),
+            WalletError::Unlock(e) => write!(f, "unable to keep the wallet unlocked: {}", e),
+        }
+    }
+}
+
+impl Error for WalletError {}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct WalletEncryption {
+    kdf: KdfParams,
+    check: Vec<u8>,
+}
+
+// Keys are derived from the BIP39 entropy in `entropy`, which is sealed with
+// HD_SEED_AAD once the wallet is encrypted. `next_index` and
+// `next_change_index` are the indexes of the next receive and change
+// addresses to derive.
+#[derive(Clone, Serialize, Deserialize)]
+struct HdSeed {
+    entropy: Vec<u8>,
+    next_index: u32,
+    next_change_index: u32,
+}
+
+// Layout of HdSeed before change addresses.
+#[derive(Deserialize)]
+struct HdSeedV1 {
+    entropy: Vec<u8>,
+    next_index: u32,
+}
+
+impl From<HdSeedV1> for HdSeed {
+    fn from(hd_seed: HdSeedV1) -> Self {
+        Self {
+            entropy: hd_seed.entropy,
+            next_index: hd_seed.next_index,
+            next_change_index: 0,
+        }
+    }
+}
+
+// Layout of a wallet file after WALLET_FILE_MAGIC and WALLET_FILE_VERSION.
+// Older files are a bare bincode map of unencrypted wallets. Once encrypted,
+// each Wallet::pkcs8 holds the sealed key with its public key as associated
+// data.
+#[derive(Serialize, Deserialize)]
+struct WalletFile {
+    encryption: Option<WalletEncryption>,
+    hd_seed: Option<HdSeed>,
+    wallets: HashMap<String, Wallet>,
+}
+
+// Version 4 layout, from before change addresses.
+#[derive(Deserialize)]
+struct WalletFileV4 {
+    encryption: Option<WalletEncryption>,
+    hd_seed: Option<HdSeedV1>,
+    wallets: HashMap<String, Wallet>,
+}
+
+// Version 3 layout, from before watch-only addresses.
+#[derive(Deserialize)]
+struct WalletFileV3 {
+    encryption: Option<WalletEncryption>,
+    hd_seed: Option<HdSeedV1>,
+    wallets: HashMap<String, WalletV2>,
+}
+
+// Version 2 layout, from before labels.
+#[derive(Deserialize)]
+struct WalletFileV2 {
+    encryption: Option<WalletEncryption>,
+    hd_seed: Option<HdSeedV1>,
+    wallets: HashMap<String, WalletV1>,
+}
+
+// Version 1 layout, from before HD seeds.
+#[derive(Deserialize)]
+struct WalletFileV1 {
+    encryption: Option<WalletEncryption>,
+    wallets: HashMap<String, WalletV1>,
+}
+
 pub struct Wallets {
+    name: String,
     wallets: HashMap<String, Wallet>,
+    encryption: Option<WalletEncryption>,
+    hd_seed: Option<HdSeed>,
+    unlock_key: Option<Vec<u8>>,
 }
 
 impl Wallets {
-    pub fn new() -> Self {
+    // Opens the wallet called `name`, which must exist unless it is the
+    // default wallet. Use open to also check that it is loaded.
+    pub fn new(name: &str) -> Self {
         let mut wallets = Wallets {
+            name: String::from(name),
             wallets: HashMap::new(),
+            encryption: None,
+            hd_seed: None,
+            unlock_key: None,
         };
 
         wallets.load_from_file();
+        wallets.load_unlock_session();
+        wallets
+    }
+
+    // An unencrypted wallet holding `keys` that is never saved.
+    #[cfg(test)]
+    pub fn temporary(keys: &[Wallet]) -> Self {
+        Wallets {
+            name: String::from(DEFAULT_WALLET),
+            wallets: keys
+                .iter()
+                .map(|key| (key.get_address(), key.clone()))
+                .collect(),
+            encryption: None,
+            hd_seed: None,
+            unlock_key: None,
+        }
+    }
+
+    // Opens a loaded wallet, the default wallet when `name` is None.
+    pub fn open(name: Option<&str>) -> Result<Self, WalletError> {
+        let name = name.unwrap_or(DEFAULT_WALLET);
+        if name != DEFAULT_WALLET {
+            check_wallet_name(name)?;
+            if !wallet_path(name, WALLET_EXTENSION).exists() {
+                return Err(WalletError::WalletNotFound(String::from(name)));
+            }
+            if !read_loaded_wallets().iter().any(|loaded| loaded == name) {
+                return Err(WalletError::NotLoaded(String::from(name)));
+            }
+        }
+        Ok(Wallets::new(name))
+    }
+
+    // Creates an empty named wallet and loads it.
+    pub fn create_named(name: &str) -> Result<(), WalletError> {
+        check_wallet_name(name)?;
+        if name == DEFAULT_WALLET || wallet_path(name, WALLET_EXTENSION).exists() {
+            return Err(WalletError::WalletExists(String::from(name)));
+        }
+        fs::create_dir_all(current_dir().unwrap().join(WALLETS_DIR))
+            .expect("unable to create the wallets directory");
+        Wallets::new(name).save_to_file();
+        Wallets::load(name)
+    }
+
+    pub fn load(name: &str) -> Result<(), WalletError> {
+        if name == DEFAULT_WALLET {
+            return Err(WalletError::AlreadyLoaded(String::from(name)));
+        }
+        check_wallet_name(name)?;
+        if !wallet_path(name, WALLET_EXTENSION).exists() {
+            return Err(WalletError::WalletNotFound(String::from(name)));
+        }
+        let mut loaded = read_loaded_wallets();
+        if loaded.iter().any(|loaded| loaded == name) {
+            return Err(WalletError::AlreadyLoaded(String::from(name)));
+        }
+        loaded.push(String::from(name));
+        write_loaded_wallets(&loaded);
+        Ok(())
+    }
+
+    // Unloading also locks the wallet.
+    pub fn unload(name: &str) -> Result<(), WalletError> {
+        if name == DEFAULT_WALLET {
+            return Err(WalletError::DefaultWallet);
+        }
+        let mut loaded = read_loaded_wallets();
+        let count = loaded.len();
+        loaded.retain(|loaded| loaded != name);
+        if loaded.len() == count {
+            return Err(WalletError::NotLoaded(String::from(name)));
+        }
+        write_loaded_wallets(&loaded);
+        unlock_agent::lock(name);
+        Ok(())
+    }
+
+    // Names of the wallets on disk, sorted, with whether each is loaded.
+    pub fn list() -> Vec<(String, bool)> {
+        let loaded = read_loaded_wallets();
+        let mut wallets = vec![(String::from(DEFAULT_WALLET), true)];
+        let entries = fs::read_dir(current_dir().unwrap().join(WALLETS_DIR))
+            .into_iter()
+            .flatten()
+            .flatten();
+        let mut named = vec![];
+        for entry in entries {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(WALLET_EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                let is_loaded = loaded.iter().any(|loaded| loaded == name);
+                named.push((String::from(name), is_loaded));
+            }
+        }
+        named.sort();
+        wallets.extend(named);
         wallets
     }
 
-    pub fn create_wallets(&mut self) -> String {
-        let wallet = Wallet::new();
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // Gives the wallet an HD seed if it has none and returns its mnemonic.
+    // Returns None when the wallet already has a seed.
+    pub fn init_hd_seed(&mut self) -> Result<Option<String>, WalletError> {
+        if self.hd_seed.is_some() {
+            return Ok(None);
+        }
+        let entropy = generate_entropy();
+        let mnemonic = to_mnemonic(&entropy).to_string();
+        self.hd_seed = Some(HdSeed {
+            entropy: self.seal_secret(entropy, HD_SEED_AAD)?,
+            next_index: 0,
+            next_change_index: 0,
+        });
+        self.save_to_file();
+        Ok(Some(mnemonic))
+    }
+
+    // Derives the next address from the HD seed, see init_hd_seed.
+    pub fn create_wallets(&mut self, label: &str) -> Result<String, WalletError> {
+        let seed = self.get_hd_seed()?;
+        let hd_seed = self.hd_seed.as_mut().unwrap();
+        let mut wallet = Wallet::from_pkcs8(derive_pkcs8(&seed, RECEIVE_CHAIN, hd_seed.next_index));
+        wallet.label = String::from(label);
+        hd_seed.next_index += 1;
+
         let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
+        self.add_wallet(wallet)?;
         self.save_to_file();
-        address
+        Ok(address)
+    }
+
+    // Derives a new address on the change chain. Returns None when the
+    // wallet has no HD seed. The address is not saved, so call save_to_file
+    // once the transaction paying it is sent, and a failed send doesn't use
+    // up the index.
+    pub fn create_change_address(&mut self) -> Result<Option<String>, WalletError> {
+        if self.hd_seed.is_none() {
+            return Ok(None);
+        }
+        let seed = self.get_hd_seed()?;
+        let hd_seed = self.hd_seed.as_mut().unwrap();
+        let mut wallet = Wallet::from_pkcs8(derive_pkcs8(
+            &seed,
+            CHANGE_CHAIN,
+            hd_seed.next_change_index,
+        ));
+        wallet.kind = AddressKind::Change;
+        hd_seed.next_change_index += 1;
+
+        let address = wallet.get_address();
+        self.add_wallet(wallet)?;
+        Ok(Some(address))
+    }
+
+    // Recreates an empty wallet from `phrase`. On both the receive and change
+    // chains, addresses are derived until GAP_LIMIT in a row fail `is_used`,
+    // and every address up to the last used one is kept. Returns the kept
+    // addresses in derivation order, receive addresses first.
+    pub fn restore(
+        &mut self,
+        phrase: &str,
+        is_used: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<String>, WalletError> {
+        if self.hd_seed.is_some() || !self.wallets.is_empty() {
+            return Err(WalletError::NotEmpty);
+        }
+        let mnemonic = bip39::Mnemonic::parse(phrase).map_err(WalletError::InvalidMnemonic)?;
+        let entropy = mnemonic.to_entropy();
+        let seed = entropy_to_seed(&entropy);
+
+        let scan = |chain: u32| {
+            let mut derived = vec![];
+            let mut next_index = 0;
+            let mut index = 0;
+            while index < next_index + GAP_LIMIT {
+                let wallet = Wallet::from_pkcs8(derive_pkcs8(&seed, chain, index));
+                if is_used(&hash_pub_key(wallet.get_pub_key())) {
+                    next_index = index + 1;
+                }
+                derived.push(wallet);
+                index += 1;
+            }
+            derived.truncate(next_index as usize);
+            (derived, next_index)
+        };
+        let (mut derived, next_index) = scan(RECEIVE_CHAIN);
+        let (change, next_change_index) = scan(CHANGE_CHAIN);
+        derived.extend(change.into_iter().map(|mut wallet| {
+            wallet.kind = AddressKind::Change;
+            wallet
+        }));
+
+        self.hd_seed = Some(HdSeed {
+            entropy: self.seal_secret(entropy, HD_SEED_AAD)?,
+            next_index,
+            next_change_index,
+        });
+        let mut addresses = vec![];
+        for wallet in derived {
+            addresses.push(wallet.get_address());
+            self.add_wallet(wallet)?;
+        }
+        self.save_to_file();
+        Ok(addresses)
+    }
+
+    fn add_wallet(&mut self, mut wallet: Wallet) -> Result<(), WalletError> {
+        if wallet.kind != AddressKind::WatchOnly {
+            wallet.pkcs8 = self.seal_secret(wallet.pkcs8, &wallet.pub_key)?;
+        }
+        self.wallets.insert(wallet.get_address(), wallet);
+        Ok(())
+    }
+
+    // Encrypts `secret` when the wallet is encrypted, which needs it unlocked.
+    fn seal_secret(&self, secret: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, WalletError> {
+        if self.encryption.is_none() {
+            return Ok(secret);
+        }
+        let key = self.unlock_key.as_ref().ok_or(WalletError::Locked)?;
+        Ok(seal(key, &secret, aad))
+    }
+
+    fn get_hd_seed(&self) -> Result<[u8; 64], WalletError> {
+        let hd_seed = self.hd_seed.as_ref().expect("wallet has no HD seed");
+        if self.encryption.is_none() {
+            return Ok(entropy_to_seed(&hd_seed.entropy));
+        }
+        let key = self.unlock_key.as_ref().ok_or(WalletError::Locked)?;
+        let entropy =
+            open(key, &hd_seed.entropy, HD_SEED_AAD).expect("unable to decrypt the HD seed");
+        Ok(entropy_to_seed(&entropy))
     }
 
     pub fn get_addresses(&self) -> Vec<String> {
         let mut addresses = vec![];
 
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone());
         }
         addresses
     }
 
+    // Adds the key from encode_private_key and returns its address. A
+    // watch-only entry for the address becomes spendable.
+    pub fn import_private_key(&mut self, encoded: &str, label: &str) -> Result<String, WalletError> {
+        let mut wallet = Wallet::from_pkcs8(decode_private_key(encoded)?);
+        let address = wallet.get_address();
+        match self.wallets.get(&address) {
+            Some(existing) if existing.kind != AddressKind::WatchOnly => {
+                return Err(WalletError::AddressExists(address));
+            }
+            Some(existing) if label.is_empty() => wallet.label = existing.label.clone(),
+            _ => wallet.label = String::from(label),
+        }
+        self.add_wallet(wallet)?;
+        self.save_to_file();
+        Ok(address)
+    }
+
+    pub fn import_address(&mut self, address: &str, label: &str) -> Result<(), WalletError> {
+        if self.wallets.contains_key(address) {
+            return Err(WalletError::AddressExists(String::from(address)));
+        }
+        let mut wallet = Wallet::watch_only(address);
+        wallet.label = String::from(label);
+        self.add_wallet(wallet)?;
+        self.save_to_file();
+        Ok(())
+    }
+
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<(), WalletError> {
+        let wallet = self
+            .wallets
+            .get_mut(address)
+            .ok_or_else(|| WalletError::UnknownAddress(String::from(address)))?;
+        wallet.label = String::from(label);
+        self.save_to_file();
+        Ok(())
+    }
+
+    pub fn get_pub_key_hashes(&self) -> HashSet<Vec<u8>> {
+        self.wallets
+            .values()
+            .map(|wallet| wallet.pub_key_hash.clone())
+            .collect()
+    }
+
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         if let Some(wallet) = self.wallets.get(address) {
             return Some(wallet);
@@ -126,32 +693,535 @@ impl Wallets {
         None
     }
 
+    // Returns the PKCS#8 private key of `address`, decrypting it when the
+    // wallet is encrypted.
+    pub fn get_private_key(&self, address: &str) -> Result<Vec<u8>, WalletError> {
+        let wallet = self
+            .get_wallet(address)
+            .ok_or_else(|| WalletError::UnknownAddress(String::from(address)))?;
+        if wallet.kind == AddressKind::WatchOnly {
+            return Err(WalletError::WatchOnly(String::from(address)));
+        }
+        if self.encryption.is_none() {
+            return Ok(wallet.pkcs8.clone());
+        }
+        let key = self.unlock_key.as_ref().ok_or(WalletError::Locked)?;
+        Ok(open(key, &wallet.pkcs8, &wallet.pub_key).expect("unable to decrypt wallet key"))
+    }
+
+    // Encrypts every private key with a key derived from `passphrase`. The
+    // wallet is locked afterwards.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<(), WalletError> {
+        if self.encryption.is_some() {
+            return Err(WalletError::AlreadyEncrypted);
+        }
+        let kdf = KdfParams::generate();
+        let key = kdf.derive_key(passphrase);
+        for wallet in self.wallets.values_mut() {
+            if wallet.kind != AddressKind::WatchOnly {
+                wallet.pkcs8 = seal(&key, &wallet.pkcs8, &wallet.pub_key);
+            }
+        }
+        if let Some(hd_seed) = self.hd_seed.as_mut() {
+            hd_seed.entropy = seal(&key, &hd_seed.entropy, HD_SEED_AAD);
+        }
+        self.encryption = Some(WalletEncryption {
+            kdf,
+            check: seal(&key, CHECK_PLAINTEXT, &[]),
+        });
+        self.save_to_file();
+        Ok(())
+    }
+
+    // Keeps the wallet unlocked for `timeout` seconds across commands, with
+    // the key held in memory by an unlock agent.
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<(), WalletError> {
+        let encryption = self.encryption.as_ref().ok_or(WalletError::NotEncrypted)?;
+        let key = encryption.kdf.derive_key(passphrase);
+        if !self.is_valid_key(&key) {
+            return Err(WalletError::WrongPassphrase);
+        }
+
+        unlock_agent::spawn(&self.name, &key, timeout).map_err(WalletError::Unlock)?;
+        self.unlock_key = Some(key);
+        Ok(())
+    }
+
+    pub fn lock(&mut self) -> Result<(), WalletError> {
+        if self.encryption.is_none() {
+            return Err(WalletError::NotEncrypted);
+        }
+        unlock_agent::lock(&self.name);
+        self.unlock_key = None;
+        Ok(())
+    }
+
+    fn is_valid_key(&self, key: &[u8]) -> bool {
+        match &self.encryption {
+            Some(encryption) => open(key, &encryption.check, &[]).is_some(),
+            None => false,
+        }
+    }
+
+    fn load_unlock_session(&mut self) {
+        if self.encryption.is_none() {
+            return;
+        }
+        self.unlock_key = unlock_agent::get_key(&self.name).filter(|key| self.is_valid_key(key));
+    }
+
     pub fn load_from_file(&mut self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+        let path = wallet_path(&self.name, WALLET_EXTENSION);
         if !path.exists() {
             return;
         }
 
-        let mut file = File::open(path).unwrap();
-        let metadata = file.metadata().expect("unable to read metadata");
-        let mut buf = vec![0; metadata.len() as usize];
-        let _ = file.read(&mut buf).expect("buffer overflow");
-        let wallets = bincode::deserialize(&buf[..]).expect("unable to deserialize the file data");
-        self.wallets = wallets;
+        let buf = fs::read(&path)
+            .unwrap_or_else(|e| panic!("unable to read {}: {}", path.display(), e));
+        self.load_from_bytes(&buf);
+    }
+
+    fn load_from_bytes(&mut self, buf: &[u8]) {
+        match buf.strip_prefix(WALLET_FILE_MAGIC) {
+            Some([WALLET_FILE_VERSION, data @ ..]) => {
+                let file: WalletFile =
+                    bincode::deserialize(data).expect("unable to deserialize the file data");
+                self.encryption = file.encryption;
+                self.hd_seed = file.hd_seed;
+                self.wallets = file.wallets;
+            }
+            Some([WALLET_FILE_VERSION_WATCH_ONLY, data @ ..]) => {
+                let file: WalletFileV4 =
+                    bincode::deserialize(data).expect("unable to deserialize the file data");
+                self.encryption = file.encryption;
+                self.hd_seed = file.hd_seed.map(HdSeed::from);
+                self.wallets = file.wallets;
+            }
+            Some([WALLET_FILE_VERSION_LABELS, data @ ..]) => {
+                let file: WalletFileV3 =
+                    bincode::deserialize(data).expect("unable to deserialize the file data");
+                self.encryption = file.encryption;
+                self.hd_seed = file.hd_seed.map(HdSeed::from);
+                self.wallets = upgrade_wallets(file.wallets);
+            }
+            Some([WALLET_FILE_VERSION_HD, data @ ..]) => {
+                let file: WalletFileV2 =
+                    bincode::deserialize(data).expect("unable to deserialize the file data");
+                self.encryption = file.encryption;
+                self.hd_seed = file.hd_seed.map(HdSeed::from);
+                self.wallets = upgrade_wallets(file.wallets);
+            }
+            Some(data) => {
+                let file: WalletFileV1 =
+                    bincode::deserialize(data).expect("unable to deserialize the file data");
+                self.encryption = file.encryption;
+                self.wallets = upgrade_wallets(file.wallets);
+            }
+            None => {
+                let wallets: HashMap<String, WalletV1> =
+                    bincode::deserialize(buf).expect("unable to deserialize the file data");
+                self.wallets = upgrade_wallets(wallets);
+            }
+        }
+    }
+
+    pub fn save_to_file(&self) {
+        write_private_file(&wallet_path(&self.name, WALLET_EXTENSION), &self.to_bytes());
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let file = WalletFile {
+            encryption: self.encryption.clone(),
+            hd_seed: self.hd_seed.clone(),
+            wallets: self.wallets.clone(),
+        };
+        let mut bytes = WALLET_FILE_MAGIC.to_vec();
+        bytes.push(WALLET_FILE_VERSION);
+        bytes.extend(bincode::serialize(&file).expect("unable to serialize the wallets"));
+        bytes
+    }
+}
+
+fn upgrade_wallets<W: Into<Wallet>>(wallets: HashMap<String, W>) -> HashMap<String, Wallet> {
+    wallets
+        .into_iter()
+        .map(|(address, wallet)| (address, wallet.into()))
+        .collect()
+}
+
+pub fn wallet_path(name: &str, extension: &str) -> PathBuf {
+    let dir = current_dir().unwrap();
+    if name == DEFAULT_WALLET {
+        return dir.join(format!("wallet.{}", extension));
+    }
+    dir.join(WALLETS_DIR).join(format!("{}.{}", name, extension))
+}
+
+// Names end up in file paths, so only plain characters are allowed.
+fn check_wallet_name(name: &str) -> Result<(), WalletError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_WALLET_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(WalletError::InvalidWalletName(String::from(name)));
+    }
+    Ok(())
+}
+
+fn read_loaded_wallets() -> Vec<String> {
+    let path = current_dir()
+        .unwrap()
+        .join(WALLETS_DIR)
+        .join(LOADED_WALLETS_FILE);
+    match fs::read_to_string(path) {
+        Ok(names) => names.lines().map(String::from).collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn write_loaded_wallets(names: &[String]) {
+    let path = current_dir()
+        .unwrap()
+        .join(WALLETS_DIR)
+        .join(LOADED_WALLETS_FILE);
+    let mut contents = names.join("\n");
+    contents.push('\n');
+    fs::write(path, contents).expect("unable to write the loaded wallets");
+}
+
+// Writes through a temporary file so a crash can't leave a half-written
+// wallet, readable only by the owner on unix.
+pub fn write_private_file(path: &Path, bytes: &[u8]) {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options
+        .open(&tmp_path)
+        .unwrap_or_else(|e| panic!("unable to open {}: {}", tmp_path.display(), e));
+
+    let mut writer = BufWriter::new(file);
+    writer.write_all(bytes).unwrap();
+    writer.flush().unwrap();
+    fs::rename(&tmp_path, path)
+        .unwrap_or_else(|e| panic!("unable to write {}: {}", path.display(), e));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::components::{
+        blockchain::Blockchain,
+        coin_selection::CoinSelection,
+        test_fixtures::{mature, mine, test_wallet},
+        transaction::Transaction,
+        utxoset::UTXOSet,
+    };
+
+    // Moves into an empty directory and back when dropped. Wallets live in
+    // the working directory, so only one test may use this.
+    struct WorkingDir(PathBuf);
+
+    impl WorkingDir {
+        fn enter_temporary() -> Self {
+            let dir = env::temp_dir().join(format!("wallets-{}", Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            let previous = current_dir().unwrap();
+            env::set_current_dir(&dir).unwrap();
+            Self(previous)
+        }
+    }
+
+    impl Drop for WorkingDir {
+        fn drop(&mut self) {
+            let dir = current_dir().unwrap();
+            env::set_current_dir(&self.0).unwrap();
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    fn error<T>(result: Result<T, WalletError>) -> String {
+        result.err().unwrap().to_string()
+    }
+
+    #[test]
+    fn loads_and_unloads_named_wallets() {
+        let _dir = WorkingDir::enter_temporary();
+        let listed = |entries: &[(&str, bool)]| {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(name, loaded)| (String::from(*name), *loaded))
+                .collect();
+            assert_eq!(Wallets::list(), entries);
+        };
+        listed(&[(DEFAULT_WALLET, true)]);
+
+        Wallets::create_named("savings").unwrap();
+        let exists = error(Wallets::create_named("savings"));
+        assert_eq!(exists, "wallet savings already exists");
+        let mut wallets = Wallets::open(Some("savings")).unwrap();
+        wallets.init_hd_seed().unwrap();
+        let address = wallets.create_wallets("").unwrap();
+        listed(&[(DEFAULT_WALLET, true), ("savings", true)]);
+
+        Wallets::unload("savings").unwrap();
+        listed(&[(DEFAULT_WALLET, true), ("savings", false)]);
+        let not_loaded = "wallet savings is not loaded, load it with loadwallet";
+        assert_eq!(error(Wallets::open(Some("savings"))), not_loaded);
+        assert_eq!(error(Wallets::unload("savings")), not_loaded);
+
+        Wallets::load("savings").unwrap();
+        let loaded = error(Wallets::load("savings"));
+        assert_eq!(loaded, "wallet savings is already loaded");
+        let wallets = Wallets::open(Some("savings")).unwrap();
+        assert_eq!(wallets.get_addresses(), vec![address]);
+        assert!(Wallets::open(None).unwrap().get_addresses().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_wallet_names() {
+        for name in ["", "../savings", "my wallet"] {
+            assert!(matches!(
+                Wallets::load(name),
+                Err(WalletError::InvalidWalletName(_))
+            ));
+            assert!(matches!(
+                Wallets::open(Some(name)),
+                Err(WalletError::InvalidWalletName(_))
+            ));
+        }
+        assert!(matches!(
+            Wallets::load(DEFAULT_WALLET),
+            Err(WalletError::AlreadyLoaded(_))
+        ));
+        assert!(matches!(
+            Wallets::unload(DEFAULT_WALLET),
+            Err(WalletError::DefaultWallet)
+        ));
+    }
+
+    const ENTROPY: [u8; 16] = [7; 16];
+
+    fn test_key() -> Vec<u8> {
+        test_wallet(0).get_pkcs8().to_vec()
+    }
+
+    fn load(bytes: &[u8]) -> Wallets {
+        let mut wallets = Wallets::temporary(&[]);
+        wallets.load_from_bytes(bytes);
+        wallets
+    }
+
+    fn versioned(version: u8, data: Vec<u8>) -> Vec<u8> {
+        let mut bytes = WALLET_FILE_MAGIC.to_vec();
+        bytes.push(version);
+        bytes.extend(data);
+        bytes
+    }
+
+    // Bincode writes a struct as the tuple of its fields, so the layouts of
+    // older versions are built from tuples.
+    fn v1_wallets() -> HashMap<String, (Vec<u8>, Vec<u8>)> {
+        let wallet = Wallet::from_pkcs8(test_key());
+        HashMap::from([(wallet.get_address(), (wallet.pkcs8, wallet.pub_key))])
+    }
+
+    fn v1_hd_seed() -> Option<(Vec<u8>, u32)> {
+        Some((ENTROPY.to_vec(), 3))
+    }
+
+    fn assert_key(wallets: &Wallets, label: &str) {
+        let address = Wallet::from_pkcs8(test_key()).get_address();
+        let wallet = wallets.get_wallet(&address).unwrap();
+        assert_eq!(wallet.get_label(), label);
+        assert!(wallet.get_kind() == AddressKind::Receive);
+        assert_eq!(wallets.get_private_key(&address).unwrap(), test_key());
+    }
+
+    fn assert_hd_seed(wallets: &Wallets, next_change_index: u32) {
+        let hd_seed = wallets.hd_seed.as_ref().unwrap();
+        assert_eq!(hd_seed.entropy, ENTROPY);
+        assert_eq!(hd_seed.next_index, 3);
+        assert_eq!(hd_seed.next_change_index, next_change_index);
+    }
+
+    #[test]
+    fn private_key_round_trips() {
+        let pkcs8 = test_key();
+        let encoded = encode_private_key(&pkcs8);
+        assert_eq!(decode_private_key(&encoded).unwrap(), pkcs8);
+        assert_eq!(
+            Wallet::from_pkcs8(decode_private_key(&encoded).unwrap()).get_address(),
+            Wallet::from_pkcs8(pkcs8).get_address()
+        );
+    }
+
+    #[test]
+    fn rejects_a_private_key_with_a_bad_checksum() {
+        let mut payload = bs58::decode(encode_private_key(&test_key()))
+            .into_vec()
+            .unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(matches!(
+            decode_private_key(&base58_encode(&payload)),
+            Err(WalletError::InvalidPrivateKey)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_private_key_with_another_version() {
+        let mut payload = vec![VERSION];
+        payload.extend(test_key());
+        payload.extend(checksum(&payload));
+        assert!(matches!(
+            decode_private_key(&base58_encode(&payload)),
+            Err(WalletError::InvalidPrivateKey)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_private_key_that_is_not_a_key() {
+        for encoded in [
+            encode_private_key(b"not a pkcs8 document"),
+            String::from("0OIl"),
+            String::new(),
+        ] {
+            assert!(matches!(
+                decode_private_key(&encoded),
+                Err(WalletError::InvalidPrivateKey)
+            ));
+        }
+    }
+
+    #[test]
+    fn reads_a_file_without_magic() {
+        let wallets = load(&bincode::serialize(&v1_wallets()).unwrap());
+        assert_key(&wallets, "");
+        assert!(wallets.hd_seed.is_none());
+    }
+
+    #[test]
+    fn upgrades_version_1() {
+        let mut bytes = WALLET_FILE_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(&(None::<WalletEncryption>, v1_wallets())).unwrap());
+        let wallets = load(&bytes);
+        assert_key(&wallets, "");
+        assert!(wallets.hd_seed.is_none());
+    }
+
+    #[test]
+    fn upgrades_version_2() {
+        let data = (None::<WalletEncryption>, v1_hd_seed(), v1_wallets());
+        let wallets = load(&versioned(
+            WALLET_FILE_VERSION_HD,
+            bincode::serialize(&data).unwrap(),
+        ));
+        assert_key(&wallets, "");
+        assert_hd_seed(&wallets, 0);
+    }
+
+    #[test]
+    fn upgrades_version_3() {
+        let v2_wallets: HashMap<String, (Vec<u8>, Vec<u8>, String)> = v1_wallets()
+            .into_iter()
+            .map(|(address, (pkcs8, pub_key))| (address, (pkcs8, pub_key, String::from("savings"))))
+            .collect();
+        let data = (None::<WalletEncryption>, v1_hd_seed(), v2_wallets);
+        let wallets = load(&versioned(
+            WALLET_FILE_VERSION_LABELS,
+            bincode::serialize(&data).unwrap(),
+        ));
+        assert_key(&wallets, "savings");
+        assert_hd_seed(&wallets, 0);
     }
 
-    fn save_to_file(&self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&path)
-            .expect("unable to open wallet.dat");
+    #[test]
+    fn upgrades_version_4() {
+        let mut key = Wallet::from_pkcs8(test_key());
+        key.label = String::from("savings");
+        let watched = test_wallet(1).get_address();
+        let v4_wallets = HashMap::from([
+            (key.get_address(), key),
+            (watched.clone(), Wallet::watch_only(&watched)),
+        ]);
+        let data = (None::<WalletEncryption>, v1_hd_seed(), v4_wallets);
+        let wallets = load(&versioned(
+            WALLET_FILE_VERSION_WATCH_ONLY,
+            bincode::serialize(&data).unwrap(),
+        ));
+        assert_key(&wallets, "savings");
+        assert!(wallets.get_wallet(&watched).unwrap().get_kind() == AddressKind::WatchOnly);
+        assert_hd_seed(&wallets, 0);
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let mut wallets = load(&bincode::serialize(&v1_wallets()).unwrap());
+        wallets.hd_seed = Some(HdSeed {
+            entropy: ENTROPY.to_vec(),
+            next_index: 3,
+            next_change_index: 2,
+        });
+        let wallets = load(&wallets.to_bytes());
+        assert_key(&wallets, "");
+        assert_hd_seed(&wallets, 2);
+    }
+
+    #[test]
+    fn change_goes_to_a_new_change_address_counted_in_the_balance() {
+        let mut wallets = Wallets::temporary(&[]);
+        wallets.hd_seed = Some(HdSeed {
+            entropy: ENTROPY.to_vec(),
+            next_index: 1,
+            next_change_index: 0,
+        });
+        let seed = entropy_to_seed(&ENTROPY);
+        let sender = Wallet::from_pkcs8(derive_pkcs8(&seed, RECEIVE_CHAIN, 0));
+        wallets.add_wallet(sender.clone()).unwrap();
+        let blockchain = Blockchain::temporary();
+        mine(&blockchain, &sender, &[]);
+        mature(&blockchain, &sender);
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let balance = |wallets: &Wallets| -> i32 {
+            wallets
+                .get_pub_key_hashes()
+                .iter()
+                .flat_map(|pub_key_hash| utxo_set.find_utxo(pub_key_hash))
+                .map(|utxo| utxo.get_value())
+                .sum()
+        };
+        let before = balance(&wallets);
+        let recipients = [(test_wallet(1).get_address(), 3)];
+        let tx = Transaction::utxo_transaction_many(
+            &mut wallets,
+            &sender.get_address(),
+            &recipients,
+            &utxo_set,
+            &CoinSelection::default(),
+        );
+
+        let change = Wallet::from_pkcs8(derive_pkcs8(&seed, CHANGE_CHAIN, 0));
+        assert_eq!(
+            tx.get_vout()[1].get_pub_key_hash(),
+            change.pub_key_hash.as_slice()
+        );
+        let kind = wallets
+            .get_wallet(&change.get_address())
+            .unwrap()
+            .get_kind();
+        assert!(kind == AddressKind::Change);
+        assert_eq!(wallets.hd_seed.as_ref().unwrap().next_change_index, 1);
 
-        let mut writer = BufWriter::new(file);
-        let wallet_bytes =
-            bincode::serialize(&self.wallets).expect("unable to serialize the wallets");
-        writer.write(wallet_bytes.as_slice()).unwrap();
-        let _ = writer.flush();
+        mine(&blockchain, &test_wallet(2), std::slice::from_ref(&tx));
+        assert_eq!(balance(&wallets), before - 3);
     }
 }
@@ -1,5 +1,6 @@
-use components::{blockchain::Blockchain, config::GLOBAL_CONFIG, helpers::base58_decode, server::{send_tx, Server, CENERAL_NODE}, transaction::Transaction, utxoset::UTXOSet, wallets::{convert_address, hash_pub_key, validate_address, Wallet, Wallets, ADDRESS_CHECK_SUM_LEN}};
+use components::{address_index::AddressIndex, blockchain::Blockchain, blocks::{Block, BlockHeader}, chain_export::{export_chain, import_chain}, chain_verify::{verify_chain, LEVEL_UTXO}, coin_selection::{format_outpoint, parse_outpoint, CoinSelection, Strategy}, config::GLOBAL_CONFIG, partial_transaction::PartialTransaction, server::{send_tx, Server, CENERAL_NODE}, transaction::Transaction, unlock_agent, utxo_snapshot::{dump_utxo_snapshot, load_utxo_snapshot, SnapshotInfo}, utxoset::UTXOSet, wallet_history::{SyncReport, WalletHistory}, wallets::{convert_address, encode_private_key, get_pub_key_hash, hash_pub_key, validate_address, AddressKind, Wallets}};
 use data_encoding::HEXLOWER;
+use std::{fs::File, io::{self, BufReader, IsTerminal}, path::PathBuf};
 use structopt::StructOpt;
 
 mod components;
@@ -17,20 +18,124 @@ struct Opt {
 
 #[derive(StructOpt, Debug)]
 enum Command {
-    #[structopt(name = "createblockchain", about="Create a new blockchain")]
-    Createblockchain {
-        #[structopt(short, long,name = "address", help="The address to send genesis block reward to")]
+    #[structopt(name = "createblockchain", about="Create a new blockchain from the network's genesis block")]
+    Createblockchain,
+    #[structopt(name = "createwallet", about="Create a new Wallet")]
+    Createwallet {
+        #[structopt(long, name = "label", default_value = "", help="Label to store with the address")]
+        label: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "restorewallet", about="Recreate the wallet from its mnemonic and find its used addresses on the chain")]
+    RestoreWallet {
+        #[structopt(long, name = "mnemonic", help="The recovery phrase shown when the wallet was created")]
+        mnemonic: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "newwallet", about="Create an empty named wallet and load it")]
+    NewWallet {
+        #[structopt(name = "name", help="Name of the wallet")]
+        name: String
+    },
+    #[structopt(name = "loadwallet", about="Load a named wallet so commands can use it")]
+    LoadWallet {
+        #[structopt(name = "name", help="Name of the wallet")]
+        name: String
+    },
+    #[structopt(name = "unloadwallet", about="Unload and lock a named wallet")]
+    UnloadWallet {
+        #[structopt(name = "name", help="Name of the wallet")]
+        name: String
+    },
+    #[structopt(name = "listwallets", about="List the wallets and whether they are loaded")]
+    ListWallets,
+    #[structopt(name = "setlabel", about="Set the label of a wallet address")]
+    SetLabel {
+        #[structopt(name = "address", help="The wallet address")]
         address: String,
+        #[structopt(name = "label", help="The new label, empty to remove it")]
+        label: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
     },
-    #[structopt(name = "createwallet", about="Create a new Wallet")]
-    Createwallet,
-    #[structopt(name = "getbalance", about="Get the wallet of the target address")]
+    #[structopt(name = "dumpprivkey", about="Print the private key of a wallet address")]
+    DumpPrivKey {
+        #[structopt(name = "address", help="The wallet address")]
+        address: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "importprivkey", about="Add a private key printed by dumpprivkey to the wallet")]
+    ImportPrivKey {
+        #[structopt(name = "key", help="The encoded private key")]
+        key: String,
+        #[structopt(long, name = "label", default_value = "", help="Label to store with the address")]
+        label: String,
+        #[structopt(long = "no-rescan", help="Skip scanning the UTXO set for the address")]
+        no_rescan: bool,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "importaddress", about="Watch an address without its private key")]
+    ImportAddress {
+        #[structopt(name = "address", help="The address to watch")]
+        address: String,
+        #[structopt(long, name = "label", default_value = "", help="Label to store with the address")]
+        label: String,
+        #[structopt(long = "no-rescan", help="Skip scanning the UTXO set for the address")]
+        no_rescan: bool,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "encryptwallet", about="Encrypt the wallet's private keys with a passphrase")]
+    EncryptWallet {
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "walletpassphrase", about="Unlock the encrypted wallet for a number of seconds")]
+    WalletPassphrase {
+        #[structopt(name = "timeout", help="Seconds to keep the wallet unlocked")]
+        timeout: u64,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "walletlock", about="Lock the encrypted wallet")]
+    WalletLock {
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "getbalance", about="Get the balance of an address, or of all addresses of a wallet")]
     GetBalance {
         #[structopt(short, long,name = "address", help="The wallet address")]
+        address: Option<String>,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "listunspent", about="List the outputs of an address that can be spent in the next block")]
+    ListUnspent {
+        #[structopt(name = "address", help="The wallet address")]
         address: String
     },
     #[structopt(name = "listaddresses", about="Print local wallet address")]
-    ListAddresses,
+    ListAddresses {
+        #[structopt(long, name = "change", help="Also list the change addresses")]
+        change: bool,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "listtransactions", about="List the transactions of a wallet, or touching an address")]
+    ListTransactions {
+        #[structopt(short, long,name = "address", help="Any address, looked up in the address index")]
+        address: Option<String>,
+        #[structopt(long, name = "count", default_value = "10", help="Number of wallet transactions to show, newest first")]
+        count: usize,
+        #[structopt(long, name = "skip", default_value = "0", help="Number of newest wallet transactions to skip")]
+        skip: usize,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
     #[structopt(name = "send", about="Add new block to chain")]
     Send {
         #[structopt(short, long,name = "from", help="Source wallet address")]
@@ -40,16 +145,144 @@ enum Command {
         #[structopt(short, long,name = "amount", help="Amount to send")]
         amount: i32,
         #[structopt(short, long,name = "mine", help="Mine immediately on the same node")]
-        mine: usize
+        mine: usize,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>,
+        #[structopt(long, name = "strategy", default_value = "bnb", help="Coin selection strategy: bnb, largest-first, smallest-first or random")]
+        strategy: Strategy,
+        #[structopt(long, name = "fee-per-input", default_value = "0", help="Fee paid to the miner for each input spent")]
+        fee_per_input: i32,
+        #[structopt(long, name = "inputs", use_delimiter = true, help="Comma separated outpoints (txid:vout) that must be spent")]
+        inputs: Vec<String>
+    },
+    #[structopt(name = "sendmany", about="Pay several addresses in one transaction")]
+    SendMany {
+        #[structopt(short, long,name = "from", help="Source wallet address")]
+        from: String,
+        #[structopt(short, long, name = "to", use_delimiter = true, help="Comma separated payments of the form address:amount")]
+        to: Vec<String>,
+        #[structopt(long, name = "file", help="JSON file with a list of {\"address\": ..., \"amount\": ...} payments")]
+        file: Option<String>,
+        #[structopt(short, long,name = "mine", help="Mine immediately on the same node")]
+        mine: usize,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>,
+        #[structopt(long, name = "strategy", default_value = "bnb", help="Coin selection strategy: bnb, largest-first, smallest-first or random")]
+        strategy: Strategy,
+        #[structopt(long, name = "fee-per-input", default_value = "0", help="Fee paid to the miner for each input spent")]
+        fee_per_input: i32,
+        #[structopt(long, name = "inputs", use_delimiter = true, help="Comma separated outpoints (txid:vout) that must be spent")]
+        inputs: Vec<String>
+    },
+    #[structopt(name = "createrawtransaction", about="Build an unsigned transaction to sign with signrawtransaction")]
+    CreateRawTransaction {
+        #[structopt(short, long,name = "from", help="Address whose outputs are spent, which may be watch-only")]
+        from: String,
+        #[structopt(short, long, name = "to", use_delimiter = true, help="Comma separated payments of the form address:amount")]
+        to: Vec<String>,
+        #[structopt(long, name = "file", help="JSON file with a list of {\"address\": ..., \"amount\": ...} payments")]
+        file: Option<String>,
+        #[structopt(long, name = "change-address", help="Address receiving the change, defaults to the sender")]
+        change_address: Option<String>,
+        #[structopt(long, name = "strategy", default_value = "bnb", help="Coin selection strategy: bnb, largest-first, smallest-first or random")]
+        strategy: Strategy,
+        #[structopt(long, name = "fee-per-input", default_value = "0", help="Fee paid to the miner for each input spent")]
+        fee_per_input: i32,
+        #[structopt(long, name = "inputs", use_delimiter = true, help="Comma separated outpoints (txid:vout) that must be spent")]
+        inputs: Vec<String>
+    },
+    #[structopt(name = "signrawtransaction", about="Sign the inputs of a raw transaction the wallet has keys for, without the chain")]
+    SignRawTransaction {
+        #[structopt(name = "transaction", help="Hex encoded raw transaction")]
+        transaction: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "sendrawtransaction", about="Send a fully signed raw transaction to the central node")]
+    SendRawTransaction {
+        #[structopt(name = "transaction", help="Hex encoded raw transaction")]
+        transaction: String,
+        #[structopt(long, name = "wallet", help="Name of a loaded wallet to record the transaction in, defaults to the default wallet")]
+        wallet: Option<String>
+    },
+    #[structopt(name = "decoderawtransaction", about="Print the inputs, outputs and signing state of a raw transaction")]
+    DecodeRawTransaction {
+        #[structopt(name = "transaction", help="Hex encoded raw transaction")]
+        transaction: String
+    },
+    #[structopt(name = "generate", about="Mine blocks paying the reward to an address")]
+    Generate {
+        #[structopt(name = "count", help="Number of blocks to mine")]
+        count: usize,
+        #[structopt(short, long, name = "address", help="The address to send the block rewards to")]
+        address: String
     },
     #[structopt(name = "printchain", about="Print blockchain all block")]
     Printchain,
+    #[structopt(name = "getblock", about="Print a block by height or hash")]
+    GetBlock {
+        #[structopt(long, name = "height", help="Height of the block on the active chain")]
+        height: Option<usize>,
+        #[structopt(long, name = "hash", help="Hash of the block")]
+        hash: Option<String>
+    },
+    #[structopt(name = "gettransaction", about="Print a transaction from the chain")]
+    GetTransaction {
+        #[structopt(short, long,name = "txid", help="The transaction id in hex")]
+        txid: String
+    },
     #[structopt(name = "reindexutxo", about="rebuild UTXO index set")]
     Reindexutxo,
+    #[structopt(name = "reindex", about="Rebuild the height and transaction indexes, UTXO set and address index")]
+    Reindex,
+    #[structopt(name = "invalidateblock", about="Mark a block invalid and roll the chain back to its parent")]
+    InvalidateBlock {
+        #[structopt(name = "hash", help="Hash of the block to invalidate")]
+        hash: String
+    },
+    #[structopt(name = "exportchain", about="Write the active chain to a file")]
+    ExportChain {
+        #[structopt(name = "file", parse(from_os_str), help="File to write the blocks to")]
+        file: PathBuf,
+        #[structopt(long, help="Compress the blocks with gzip")]
+        compress: bool
+    },
+    #[structopt(name = "importchain", about="Validate and connect the blocks from an exported file")]
+    ImportChain {
+        #[structopt(name = "file", parse(from_os_str), help="File written by exportchain")]
+        file: PathBuf
+    },
+    #[structopt(name = "dumputxo", about="Write the UTXO set at a block to a snapshot file")]
+    DumpUtxo {
+        #[structopt(name = "file", parse(from_os_str), help="File to write the snapshot to")]
+        file: PathBuf,
+        #[structopt(long, name = "height", help="Height of the active block to snapshot, defaults to the tip")]
+        height: Option<usize>
+    },
+    #[structopt(name = "loadutxo", about="Create a new blockchain from a trusted UTXO snapshot")]
+    LoadUtxo {
+        #[structopt(name = "file", parse(from_os_str), help="File written by dumputxo")]
+        file: PathBuf,
+        #[structopt(long, name = "commitment", help="Commitment hash the snapshot must match")]
+        commitment: String
+    },
+    #[structopt(name = "verifychain", about="Check the stored blocks, indexes and UTXO set")]
+    VerifyChain {
+        #[structopt(long, name = "depth", help="Number of blocks to check from the tip, defaults to all stored blocks")]
+        depth: Option<usize>,
+        #[structopt(long, name = "level", default_value = "3", help="0: linkage, heights and indexes, 1: also proof of work, 2: also signatures, 3: also compare a recomputed UTXO set")]
+        level: u8
+    },
     #[structopt(name = "startnode", about="Start a node")]
     StartNode {
         #[structopt(short, long,name = "miner", help="Enable mining mode and send reward to ADDRESS")]
         miner: Option<String>
+    },
+    // Started by walletpassphrase, see unlock_agent.
+    #[structopt(name = "unlockagent", setting = structopt::clap::AppSettings::Hidden)]
+    UnlockAgent {
+        wallet: String,
+        timeout: u64
     }
 }
 
@@ -57,43 +290,216 @@ fn main() {
     env_logger::builder().filter_level(log::LevelFilter::Info).init();
 
     let opt = Opt::from_args();
+    // Checks the environment before any command runs.
+    once_cell::sync::Lazy::force(&GLOBAL_CONFIG);
 
     match opt.command {
-        Command::Createblockchain { address } => {
-            let blockchain = Blockchain::init(&address);
-            let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+        Command::Createblockchain => {
+            Blockchain::init();
             println!("=> Blockchain created");
         },
-        Command::Createwallet => {
-            let mut wallet = Wallets::new();
-            let address = wallet.create_wallets();
+        Command::Createwallet { label, wallet } => {
+            let mut wallet = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let mnemonic = wallet.init_hd_seed().unwrap_or_else(|e| panic!("=> Error: {}", e));
+            if let Some(mnemonic) = mnemonic {
+                println!("=> Your recovery phrase is: {mnemonic}");
+                println!("=> Write it down, it restores every address created from now on");
+            }
+            let address = wallet.create_wallets(&label).unwrap_or_else(|e| panic!("=> Error: {}", e));
             println!("=> Your new address is: {address}");
         },
-        Command::GetBalance { address } => {
+        Command::RestoreWallet { mnemonic, wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let blockchain = Blockchain::new();
+            let used = blockchain.find_used_pub_key_hashes();
+            match wallets.restore(&mnemonic, |pub_key_hash| used.contains(pub_key_hash)) {
+                Ok(addresses) => {
+                    for address in &addresses {
+                        println!("=> Restored address: {address}");
+                    }
+                    println!("=> Restored {} addresses", addresses.len());
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+            WalletHistory::load(wallets.get_name()).reset();
+            sync_history(&wallets, &blockchain);
+        },
+        Command::NewWallet { name } => {
+            if let Err(e) = Wallets::create_named(&name) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet {} created and loaded", name);
+        },
+        Command::LoadWallet { name } => {
+            if let Err(e) = Wallets::load(&name) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet {} loaded", name);
+        },
+        Command::UnloadWallet { name } => {
+            if let Err(e) = Wallets::unload(&name) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet {} unloaded", name);
+        },
+        Command::ListWallets => {
+            for (name, loaded) in Wallets::list() {
+                let status = if loaded { "loaded" } else { "not loaded" };
+                println!("=> {name} ({status})");
+            }
+        },
+        Command::SetLabel { address, label, wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            if let Err(e) = wallets.set_label(&address, &label) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Label of {address} set");
+        },
+        Command::DumpPrivKey { address, wallet } => {
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let pkcs8 = wallets.get_private_key(&address).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            println!("=> {}", encode_private_key(&pkcs8));
+        },
+        Command::ImportPrivKey { key, label, no_rescan, wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let address = wallets.import_private_key(&key, &label).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            println!("=> Imported the key of {address}");
+            rescan_wallet(&wallets, no_rescan);
+        },
+        Command::ImportAddress { address, label, no_rescan, wallet } => {
+            if !validate_address(&address) {
+                panic!("=> Error: Address is not valid.");
+            }
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            if let Err(e) = wallets.import_address(&address, &label) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Watching {address}");
+            rescan_wallet(&wallets, no_rescan);
+        },
+        Command::EncryptWallet { wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let passphrase = read_passphrase("Passphrase: ");
+            if passphrase.is_empty() {
+                panic!("=> Error: The passphrase can't be empty");
+            }
+            if io::stdin().is_terminal() && read_passphrase("Repeat the passphrase: ") != passphrase {
+                panic!("=> Error: The passphrases don't match");
+            }
+            if let Err(e) = wallets.encrypt(&passphrase) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet encrypted and locked");
+        },
+        Command::WalletPassphrase { timeout, wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let passphrase = read_passphrase("Passphrase: ");
+            if let Err(e) = wallets.unlock(&passphrase, timeout) {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet unlocked for {} seconds", timeout);
+        },
+        Command::WalletLock { wallet } => {
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            if let Err(e) = wallets.lock() {
+                panic!("=> Error: {}", e);
+            }
+            println!("=> Wallet locked");
+        },
+        Command::GetBalance { address: Some(address), .. } => {
             let address_valid = validate_address(&address);
-            if address_valid == false {
+            if !address_valid {
                 panic!("=> Error: Address is not valid.");
             }
-            let payload = base58_decode(&address);
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+            let pub_key_hash = get_pub_key_hash(&address);
 
             let blockchain = Blockchain::new();
             let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash);
+            let utxos = utxo_set.find_utxo(pub_key_hash.as_slice());
             let mut balance = 0;
             for utxo in utxos {
                 balance += utxo.get_value();
             }
             println!("=> Balance of {address} : {balance}");
         },
-        Command::ListAddresses => {
-            let wallets = Wallets::new();
+        Command::GetBalance { address: None, wallet } => {
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let blockchain = Blockchain::new();
+            let (history, _) = sync_history(&wallets, &blockchain);
+            let (_, unconfirmed) = history.get_balances();
+            let confirmed = get_confirmed_balance(&wallets, &blockchain, &history);
+            println!("=> Balance of wallet {} : {}", wallets.get_name(), confirmed);
+            println!("=> Unconfirmed balance change : {}", unconfirmed);
+        },
+        Command::ListUnspent { address } => {
+            if !validate_address(&address) {
+                panic!("=> Error: Address is not valid.");
+            }
+            let pub_key_hash = get_pub_key_hash(&address);
+
+            let blockchain = Blockchain::new();
+            let utxo_set = UTXOSet::new(blockchain);
+            let utxos = utxo_set.find_spendable_utxos(pub_key_hash.as_slice());
+            for (outpoint, utxo) in &utxos {
+                println!("=> {} value {} height {}", format_outpoint(outpoint), utxo.get_value(), utxo.get_height());
+            }
+            println!("=> {} spendable outputs", utxos.len());
+        },
+        Command::ListAddresses { change, wallet } => {
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
             for address in wallets.get_addresses() {
-                println!("=> {address}");
+                let wallet = wallets.get_wallet(&address).unwrap();
+                if wallet.get_kind() == AddressKind::Change && !change {
+                    continue;
+                }
+                let mut line = format!("=> {address}");
+                if !wallet.get_label().is_empty() {
+                    line.push_str(&format!(" ({})", wallet.get_label()));
+                }
+                if wallet.get_kind() == AddressKind::WatchOnly {
+                    line.push_str(" [watch-only]");
+                }
+                if wallet.get_kind() == AddressKind::Change {
+                    line.push_str(" [change]");
+                }
+                println!("{line}");
+            }
+        },
+        Command::ListTransactions { address: Some(address), .. } => {
+            if !validate_address(&address) {
+                panic!("=> Error: Address is not valid.");
+            }
+            let pub_key_hash = get_pub_key_hash(&address);
+
+            if !AddressIndex::is_enabled() {
+                panic!("=> Error: Address index is disabled. Set ADDRESS_INDEX=1 to enable it.");
+            }
+            let blockchain = Blockchain::new();
+            let address_index = AddressIndex::open(&blockchain)
+                .expect("=> Error: Address index is out of date. Run reindex to rebuild it.");
+            for (height, txid) in address_index.find_transactions(pub_key_hash.as_slice()) {
+                println!("=> Height: {}, txid: {}", height, HEXLOWER.encode(txid.as_slice()));
+            }
+        },
+        Command::ListTransactions { address: None, count, skip, wallet } => {
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let blockchain = Blockchain::new();
+            let (history, _) = sync_history(&wallets, &blockchain);
+            let best_height = blockchain.get_best_height();
+            let txs = history.get_transactions();
+            for tx in txs.iter().skip(skip).take(count) {
+                println!(
+                    "=> txid: {}, confirmations: {}, received: {}, sent: {}, timestamp: {}",
+                    HEXLOWER.encode(tx.get_txid()),
+                    tx.get_confirmations(best_height),
+                    tx.get_received(),
+                    tx.get_sent(),
+                    tx.get_timestamp()
+                );
             }
+            println!("=> {} transactions in total", txs.len());
         },
-        Command::Send { from, to, amount, mine } => {
+        Command::Send { from, to, amount, mine, wallet, strategy, fee_per_input, inputs } => {
             if !validate_address(&from) {
                 panic!("=> Error: Sender address is not valid");
             }
@@ -102,56 +508,156 @@ fn main() {
                 panic!("=> Error: Receiver address is not valid");
             }
 
+            if amount <= 0 {
+                panic!("=> Error: Amount must be positive");
+            }
+            let coin_selection = parse_coin_selection(strategy, fee_per_input, &inputs);
+
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
             let blockchain = Blockchain::new();
             let utxo_set = UTXOSet::new(blockchain.clone());
 
-            let transaction = Transaction::utxo_transaction(&from, &to, amount, &utxo_set);
+            let transaction = Transaction::utxo_transaction(&mut wallets, &from, &to, amount, &utxo_set, &coin_selection);
+            submit_transaction(&wallets, &utxo_set, &from, transaction, mine);
+        },
+        Command::SendMany { from, to, file, mine, wallet, strategy, fee_per_input, inputs } => {
+            if !validate_address(&from) {
+                panic!("=> Error: Sender address is not valid");
+            }
 
-            if mine == MINE_TRUE {
-                
-                let coinbase_tx = Transaction::coinbase_tx(&from);
-                let block = blockchain.mine_block(&vec![transaction, coinbase_tx]);
+            let recipients = parse_payments(&to, file.as_deref());
+            let coin_selection = parse_coin_selection(strategy, fee_per_input, &inputs);
 
-                utxo_set.update(&block);
-            } else {
-                send_tx(CENERAL_NODE, &transaction);
-            }
-            println!("=> Success");
+            let mut wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let blockchain = Blockchain::new();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+
+            let transaction = Transaction::utxo_transaction_many(&mut wallets, &from, &recipients, &utxo_set, &coin_selection);
+            println!("=> Paying {} recipients in transaction {}", recipients.len(), HEXLOWER.encode(transaction.get_id()));
+            submit_transaction(&wallets, &utxo_set, &from, transaction, mine);
         },
-        Command::Printchain => {
-            let mut block_iterator = Blockchain::new().iterator();
-            loop {
-                let option = block_iterator.next();
-                if option.is_none() {
-                    break;
-                }
+        Command::CreateRawTransaction { from, to, file, change_address, strategy, fee_per_input, inputs } => {
+            if !validate_address(&from) {
+                panic!("=> Error: Sender address is not valid");
+            }
+            let change_address = change_address.unwrap_or_else(|| from.clone());
+            if !validate_address(&change_address) {
+                panic!("=> Error: Change address is not valid");
+            }
+            let recipients = parse_payments(&to, file.as_deref());
+            let coin_selection = parse_coin_selection(strategy, fee_per_input, &inputs);
 
-                let block = option.unwrap();
-                println!("=> Prev block hash: {}", block.get_prev_block_hash());
-                println!("=> Current block hash: {}", block.get_hash());
-                println!("=> Current block timestamp: {}", block.get_timestamp());
-                for tx in block.get_transactions() {
-                    let cur_txid_hex = HEXLOWER.encode(tx.get_id());
-                    println!("- Transaction txid_hex: {}", cur_txid_hex);
-
-                    if tx.is_coinbase() == false {
-                        for input in tx.get_vin() {
-                            let txid_hex = HEXLOWER.encode(input.get_txid());
-                            let pub_key_hash = hash_pub_key(input.get_pub_key());
-                            let adddress = convert_address(pub_key_hash.as_slice());
-                            println!("-- Input txid = {}, vout = {}, from = {}", txid_hex, input.get_vout(), adddress);
-                        }
-                    }
+            let blockchain = Blockchain::new();
+            let utxo_set = UTXOSet::new(blockchain);
+            let partial = PartialTransaction::create(&utxo_set, &from, &recipients, &change_address, &coin_selection).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            println!("=> Spending {} inputs with a fee of {}", partial.get_transaction().get_vin().len(), partial.get_fee());
+            println!("{}", partial.encode());
+        },
+        Command::SignRawTransaction { transaction, wallet } => {
+            let mut partial = PartialTransaction::decode(&transaction).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let signed = partial.sign(&wallets).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            if partial.is_complete() && !partial.verify() {
+                panic!("=> Error: The signatures don't match the previous outputs");
+            }
+            println!("=> Signed {} inputs, complete: {}", signed, partial.is_complete());
+            println!("{}", partial.encode());
+        },
+        Command::SendRawTransaction { transaction, wallet } => {
+            let partial = PartialTransaction::decode(&transaction).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let transaction = partial.into_transaction().unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let wallets = Wallets::open(wallet.as_deref()).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let blockchain = Blockchain::new();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            if !transaction.verify_with(|outpoint| utxo_set.get_entry(outpoint)) {
+                panic!("=> Error: The transaction spends unknown outputs or has invalid signatures");
+            }
 
-                    for output in tx.get_vout() {
-                        let pub_key_hash = output.get_pub_key_hash();
-                        let address = convert_address(pub_key_hash);
-                        println!("-- output value = {}, to = {}", output.get_value(), address);
+            send_tx(CENERAL_NODE, &transaction).unwrap_or_else(|e| panic!("=> Error: Unable to send the transaction to {}: {}", CENERAL_NODE, e));
+            let mut history = WalletHistory::load(wallets.get_name());
+            history.add_unconfirmed(&transaction, |outpoint| utxo_set.get_entry(outpoint), &wallets.get_pub_key_hashes());
+            println!("=> Sent transaction {}", HEXLOWER.encode(transaction.get_id()));
+        },
+        Command::DecodeRawTransaction { transaction } => {
+            let partial = PartialTransaction::decode(&transaction).unwrap_or_else(|e| panic!("=> Error: {}", e));
+            let tx = partial.get_transaction();
+            if partial.is_complete() {
+                println!("=> txid: {}", HEXLOWER.encode(tx.get_id()));
+            }
+            for vin in tx.get_vin() {
+                let outpoint = vin.get_outpoint();
+                let prev_out = partial.get_prev_out(&outpoint).unwrap();
+                println!("=> input {} value {} from {} signed: {}", format_outpoint(&outpoint), prev_out.get_value(), convert_address(prev_out.get_pub_key_hash()), vin.is_signed());
+            }
+            for out in tx.get_vout() {
+                println!("=> output value {} to {}", out.get_value(), convert_address(out.get_pub_key_hash()));
+            }
+            println!("=> fee: {}", partial.get_fee());
+            println!("=> complete: {}", partial.is_complete());
+            if partial.is_complete() {
+                println!("=> signatures valid: {}", partial.verify());
+            }
+        },
+        Command::Generate { count, address } => {
+            if !validate_address(&address) {
+                panic!("=> Error: Address is not valid.");
+            }
+            let blockchain = Blockchain::new();
+            match blockchain.generate(count, &address) {
+                Ok(blocks) => {
+                    for block in &blocks {
+                        println!("=> Mined block {} at height {}", block.get_hash(), block.get_height());
                     }
-                    println!();
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::Printchain => {
+            let blockchain = Blockchain::new();
+            for height in (0..=blockchain.get_best_height()).rev() {
+                let hash = blockchain.get_block_hash_by_height(height).unwrap();
+                match blockchain.get_block(hash.as_bytes()) {
+                    Some(block) => print_block(&block),
+                    None => print_pruned_header(&blockchain.get_header(hash.as_bytes()).unwrap()),
                 }
             }
         },
+        Command::GetBlock { height, hash } => {
+            let blockchain = Blockchain::new();
+            let hash = match (height, hash) {
+                (Some(height), _) => blockchain.get_block_hash_by_height(height),
+                (None, Some(hash)) => Some(hash),
+                (None, None) => panic!("=> Error: Either --height or --hash is required"),
+            };
+            let header = hash
+                .and_then(|hash| blockchain.get_header(hash.as_bytes()))
+                .expect("=> Error: Block not found");
+            println!("=> Height: {}", header.get_height());
+            match blockchain.get_block(header.get_hash().as_bytes()) {
+                Some(block) => print_block(&block),
+                None => print_pruned_header(&header),
+            }
+        },
+        Command::GetTransaction { txid } => {
+            let txid = HEXLOWER.decode(txid.as_bytes()).expect("=> Error: txid is not valid hex");
+            let blockchain = Blockchain::new();
+            let (block_hash, position) = blockchain
+                .find_transaction_location(txid.as_slice())
+                .expect("=> Error: Transaction not found");
+            println!("=> Block hash: {}, position: {}", block_hash, position);
+            match blockchain.find_transaction(txid.as_slice()) {
+                Some(tx) => print_transaction(&tx),
+                None => println!("=> The block holding this transaction has been pruned"),
+            }
+        },
+        Command::Reindex => {
+            let blockchain = Blockchain::new();
+            blockchain.reindex_indexes();
+            let utxo_set = UTXOSet::new(blockchain);
+            utxo_set.reindex();
+            let count = utxo_set.count_transaction();
+            println!("=> Done! There are {} transaction in the UTXO set.", count);
+        },
         Command::Reindexutxo => {
             let blockchain = Blockchain::new();
             let utxo_set = UTXOSet::new(blockchain);
@@ -159,9 +665,68 @@ fn main() {
             let count = utxo_set.count_transaction();
             println!("=> Done! There are {} transaction in the UTXO set.", count);
         },
+        Command::InvalidateBlock { hash } => {
+            let blockchain = Blockchain::new();
+            match blockchain.invalidate_block(&hash) {
+                Ok(disconnected) => {
+                    for block in &disconnected {
+                        println!("=> Disconnected block {} at height {}", block.get_hash(), block.get_height());
+                    }
+                    println!("=> Tip is now {} at height {}", blockchain.get_latest_blk_hash(), blockchain.get_best_height());
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::ExportChain { file, compress } => {
+            let blockchain = Blockchain::new();
+            match export_chain(&blockchain, &file, compress) {
+                Ok(count) => println!("=> Exported {} blocks to {}", count, file.display()),
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::ImportChain { file } => {
+            match import_chain(&file) {
+                Ok((blockchain, count)) => {
+                    println!("=> Read {} blocks from {}", count, file.display());
+                    println!("=> Tip is now {} at height {}", blockchain.get_latest_blk_hash(), blockchain.get_best_height());
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::DumpUtxo { file, height } => {
+            let blockchain = Blockchain::new();
+            match dump_utxo_snapshot(&blockchain, &file, height) {
+                Ok(info) => print_snapshot_info(&info),
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::LoadUtxo { file, commitment } => {
+            match load_utxo_snapshot(&file, &commitment) {
+                Ok((_, info)) => {
+                    print_snapshot_info(&info);
+                    println!("=> Blockchain created from snapshot");
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
+        Command::VerifyChain { depth, level } => {
+            if level > LEVEL_UTXO {
+                panic!("=> Error: level must be between 0 and {}", LEVEL_UTXO);
+            }
+            let blockchain = Blockchain::open_without_repair();
+            match verify_chain(&blockchain, depth, level) {
+                Ok(report) => {
+                    println!("=> Checked {} blocks, no problems found", report.blocks_checked);
+                    if level >= LEVEL_UTXO && !report.utxo_checked {
+                        println!("=> UTXO set not compared: old block bodies have been pruned");
+                    }
+                }
+                Err(e) => panic!("=> Error: {}", e),
+            }
+        },
         Command::StartNode { miner } => {
             if let Some(addr) = miner {
-                if validate_address(&addr) == false {
+                if !validate_address(&addr) {
                     panic!("=> Wrong minner adderss!!!");
                 }
                 println!("=> Mining is on. Address to receive rewards: {}", addr);
@@ -172,5 +737,170 @@ fn main() {
             let socket_addr = GLOBAL_CONFIG.get_node_addr();
             Server::new(blockchain).start(&socket_addr);
         },
+        Command::UnlockAgent { wallet, timeout } => {
+            unlock_agent::run(&wallet, timeout);
+        },
+    }
+}
+
+fn parse_coin_selection(strategy: Strategy, fee_per_input: i32, inputs: &[String]) -> CoinSelection {
+    if fee_per_input < 0 {
+        panic!("=> Error: Fee can't be negative");
+    }
+    let inputs = inputs.iter().map(|input| parse_outpoint(input).unwrap_or_else(|| panic!("=> Error: {} is not an outpoint of the form txid:vout", input))).collect();
+    CoinSelection { strategy, fee_per_input, inputs }
+}
+
+// Collects the address:amount pairs of `to` and the payments in `file`.
+fn parse_payments(to: &[String], file: Option<&str>) -> Vec<(String, i32)> {
+    let mut recipients = vec![];
+    for payment in to {
+        let (address, amount) = payment.rsplit_once(':').unwrap_or_else(|| panic!("=> Error: {} is not a payment of the form address:amount", payment));
+        let amount = amount.parse().unwrap_or_else(|_| panic!("=> Error: {} is not a valid amount", amount));
+        recipients.push((String::from(address), amount));
+    }
+    if let Some(file) = file {
+        recipients.extend(read_payments(file));
+    }
+    if recipients.is_empty() {
+        panic!("=> Error: No payments given, use --to or --file");
+    }
+    for (address, amount) in &recipients {
+        if !validate_address(address) {
+            panic!("=> Error: Receiver address {} is not valid", address);
+        }
+        if *amount <= 0 {
+            panic!("=> Error: Amount paid to {} must be positive", address);
+        }
+    }
+    recipients
+}
+
+// Reads payments from a JSON list of {"address": ..., "amount": ...} objects.
+fn read_payments(path: &str) -> Vec<(String, i32)> {
+    let file = File::open(path).unwrap_or_else(|e| panic!("=> Error: unable to open {}: {}", path, e));
+    let payments: serde_json::Value = serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| panic!("=> Error: {} is not valid JSON: {}", path, e));
+    let payments = payments.as_array().unwrap_or_else(|| panic!("=> Error: {} must hold a list of payments", path));
+    payments.iter().map(|payment| {
+        let address = payment["address"].as_str();
+        let amount = payment["amount"].as_i64().and_then(|amount| i32::try_from(amount).ok());
+        match (address, amount) {
+            (Some(address), Some(amount)) => (String::from(address), amount),
+            _ => panic!("=> Error: {} is not a payment with an address and an amount", payment),
+        }
+    }).collect()
+}
+
+// Mines the transaction into a block right away or sends it to the central
+// node, recording it in the wallet history until a block includes it.
+fn submit_transaction(wallets: &Wallets, utxo_set: &UTXOSet, from: &str, transaction: Transaction, mine: usize) {
+    let fee = transaction.get_fee(|outpoint| utxo_set.get_entry(outpoint)).unwrap_or(0);
+    println!("=> Spending {} inputs with a fee of {}", transaction.get_vin().len(), fee);
+
+    if mine == MINE_TRUE {
+        let blockchain = utxo_set.get_blockchain();
+        let coinbase_tx = Transaction::coinbase_tx(from, blockchain.get_best_height() + 1, fee);
+        if let Err(e) = blockchain.mine_block(&[transaction, coinbase_tx]) {
+            panic!("=> Error: {}", e);
+        }
+        wallets.save_to_file();
+    } else {
+        send_tx(CENERAL_NODE, &transaction).unwrap_or_else(|e| panic!("=> Error: Unable to send the transaction to {}: {}", CENERAL_NODE, e));
+        wallets.save_to_file();
+        let mut history = WalletHistory::load(wallets.get_name());
+        history.add_unconfirmed(&transaction, |outpoint| utxo_set.get_entry(outpoint), &wallets.get_pub_key_hashes());
+    }
+    println!("=> Success");
+}
+
+// Brings the wallet's history up to date with the active chain.
+fn sync_history(wallets: &Wallets, blockchain: &Blockchain) -> (WalletHistory, SyncReport) {
+    let mut history = WalletHistory::load(wallets.get_name());
+    let report = history.sync(blockchain, &wallets.get_pub_key_hashes());
+    if report.disconnected > 0 {
+        println!("=> {} blocks left the active chain, their transactions are unconfirmed again", report.disconnected);
+    }
+    if report.pruned > 0 {
+        println!("=> Warning: {} blocks have been pruned, their transactions are missing from the wallet history", report.pruned);
+    }
+    (history, report)
+}
+
+// The history misses the transactions of pruned blocks, so on a pruned chain
+// the balance comes from the wallet's outputs in the UTXO set instead.
+fn get_confirmed_balance(wallets: &Wallets, blockchain: &Blockchain, history: &WalletHistory) -> i32 {
+    if !blockchain.is_pruned() {
+        return history.get_balances().0;
+    }
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    wallets.get_pub_key_hashes().iter().flat_map(|pub_key_hash| utxo_set.find_utxo(pub_key_hash)).map(|utxo| utxo.get_value()).sum()
+}
+
+// New keys may have older transactions, so the history is rebuilt from
+// genesis now or on the next sync.
+fn rescan_wallet(wallets: &Wallets, no_rescan: bool) {
+    WalletHistory::load(wallets.get_name()).reset();
+    if no_rescan {
+        return;
+    }
+    let blockchain = Blockchain::new();
+    let (history, report) = sync_history(wallets, &blockchain);
+    let confirmed = get_confirmed_balance(wallets, &blockchain, &history);
+    println!("=> Rescanned {} blocks, the wallet balance is {}", report.connected, confirmed);
+}
+
+// Reads the passphrase from the terminal without echoing it, or a line of
+// stdin when it is not a terminal.
+fn read_passphrase(prompt: &str) -> String {
+    let passphrase = if io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt)
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map(|_| String::from(line.trim_end_matches(['\r', '\n'])))
+    };
+    passphrase.unwrap_or_else(|e| panic!("=> Error: Unable to read the passphrase: {}", e))
+}
+
+fn print_snapshot_info(info: &SnapshotInfo) {
+    println!("=> Base block: {} at height {}", info.base_hash, info.base_height);
+    println!("=> UTXO entries: {}", info.utxo_count);
+    println!("=> Commitment: {}", info.commitment);
+}
+
+fn print_block(block: &Block) {
+    println!("=> Prev block hash: {}", block.get_prev_block_hash());
+    println!("=> Current block hash: {}", block.get_hash());
+    println!("=> Current block timestamp: {}", block.get_timestamp());
+    for tx in block.get_transactions() {
+        print_transaction(tx);
+        println!();
+    }
+}
+
+fn print_pruned_header(header: &BlockHeader) {
+    println!("=> Prev block hash: {}", header.get_prev_block_hash());
+    println!("=> Current block hash: {}", header.get_hash());
+    println!("=> Current block timestamp: {}", header.get_timestamp());
+    println!("=> Block body has been pruned");
+    println!();
+}
+
+fn print_transaction(tx: &Transaction) {
+    let cur_txid_hex = HEXLOWER.encode(tx.get_id());
+    println!("- Transaction txid_hex: {}", cur_txid_hex);
+
+    if !tx.is_coinbase() {
+        for input in tx.get_vin() {
+            let txid_hex = HEXLOWER.encode(input.get_txid());
+            let pub_key_hash = hash_pub_key(input.get_pub_key());
+            let adddress = convert_address(pub_key_hash.as_slice());
+            println!("-- Input txid = {}, vout = {}, from = {}", txid_hex, input.get_vout(), adddress);
+        }
+    }
+
+    for output in tx.get_vout() {
+        let pub_key_hash = output.get_pub_key_hash();
+        let address = convert_address(pub_key_hash);
+        println!("-- output value = {}, to = {}", output.get_value(), address);
     }
 }